@@ -0,0 +1,68 @@
+//! Wall-clock abstraction, so that time-dependent behavior can be tested
+//! and previewed deterministically.
+
+use chrono::{DateTime, TimeZone, Utc};
+use std::fmt::Debug;
+
+/// Source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// Return the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// System wall-clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock frozen at a given time.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// Parse a point in time, either as seconds since the Unix epoch or in RFC 3339 format.
+pub(crate) fn parse_time(input: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(secs) = input.parse::<i64>() {
+        return Utc
+            .timestamp_opt(secs, 0)
+            .single()
+            .ok_or_else(|| format!("Unix timestamp '{}' is out of range", input));
+    }
+    DateTime::parse_from_rfc3339(input)
+        .map(|ts| ts.with_timezone(&Utc))
+        .map_err(|e| {
+            format!(
+                "'{}' is neither a Unix timestamp nor RFC 3339: {}",
+                input, e
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_parsing() {
+        let expected = Utc.timestamp_opt(1_570_000_000, 0).unwrap();
+        assert_eq!(parse_time("1570000000").unwrap(), expected);
+        assert_eq!(parse_time("2019-10-02T07:06:40Z").unwrap(), expected);
+        assert_eq!(parse_time("2019-10-02T09:06:40+02:00").unwrap(), expected);
+
+        let out_of_range = i64::max_value().to_string();
+        assert!(parse_time(&out_of_range).is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,917 @@
+//! Service configuration.
+//!
+//! Configuration is read from an optional TOML file, then individual
+//! settings can be overridden via `DUMNATI_*` environment variables.
+//! Everything is validated once at startup.
+
+use crate::{metadata, policy};
+use failure::{bail, format_err, Fallible};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Default graph-builder service port.
+static DEFAULT_GB_PORT: u16 = 8080;
+/// Default graph-builder status port.
+static DEFAULT_GB_STATUS_PORT: u16 = 9080;
+/// Default policy-engine service port.
+static DEFAULT_PE_PORT: u16 = 8081;
+/// Default policy-engine status port.
+static DEFAULT_PE_STATUS_PORT: u16 = 9081;
+/// Default maximum age of any stream graph before the graph-builder reports as not ready, in seconds.
+static DEFAULT_GB_MAX_GRAPH_AGE_SECS: u64 = 600;
+/// Default graph-builder URL, as seen by the policy-engine.
+static DEFAULT_PE_GB_URL: &str = "http://127.0.0.1:8080";
+/// Default graph-builder status URL, as seen by the policy-engine.
+static DEFAULT_PE_GB_STATUS_URL: &str = "http://127.0.0.1:9080";
+/// Default lifetime of graphs cached by the policy-engine, in seconds.
+static DEFAULT_PE_CACHE_SECS: u64 = 30;
+/// Default streams to scrape.
+static DEFAULT_STREAMS: &[&str] = &["stable", "testing", "next"];
+/// Default architectures every release must have a payload for.
+static DEFAULT_BASEARCHES: &[&str] = &["x86_64"];
+/// Default delay between upstream scrapes, in seconds.
+static DEFAULT_REFRESH_SECS: u64 = 30;
+/// Default timeout for a single upstream request, in seconds.
+static DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 10;
+/// Default number of retries for a failed upstream request.
+static DEFAULT_MAX_RETRIES: u32 = 3;
+/// Default delay before the first retry, in milliseconds.
+static DEFAULT_RETRY_INITIAL_DELAY_MS: u64 = 500;
+/// Default upper bound for delays between retries, in milliseconds.
+static DEFAULT_RETRY_MAX_DELAY_MS: u64 = 10_000;
+/// Default maximum drop in the number of releases on refresh, in percent.
+static DEFAULT_MAX_RELEASE_DROP_PERCENT: u8 = 20;
+/// Default number of graph diffs kept in memory, per stream.
+static DEFAULT_GRAPH_DIFF_HISTORY: usize = 20;
+
+/// Runtime settings, validated.
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) graph_builder: GraphBuilderSettings,
+    pub(crate) policy_engine: PolicyEngineSettings,
+    pub(crate) upstream: UpstreamSettings,
+}
+
+/// Listening sockets for a service.
+#[derive(Clone, Debug)]
+pub(crate) struct ServiceSettings {
+    pub(crate) address: IpAddr,
+    pub(crate) port: u16,
+    pub(crate) status_port: u16,
+}
+
+/// Graph-builder service.
+#[derive(Clone, Debug)]
+pub(crate) struct GraphBuilderSettings {
+    pub(crate) service: ServiceSettings,
+    pub(crate) max_graph_age: Option<Duration>,
+    pub(crate) policies: policy::PolicyChain,
+}
+
+/// Policy-engine service.
+#[derive(Clone, Debug)]
+pub(crate) struct PolicyEngineSettings {
+    pub(crate) service: ServiceSettings,
+    pub(crate) graph_builder_url: reqwest::Url,
+    pub(crate) graph_builder_status_url: reqwest::Url,
+    pub(crate) graph_cache_ttl: Duration,
+    /// Per-stream salt for client wariness.
+    pub(crate) rollout_salts: HashMap<String, String>,
+    /// Whether to serve the `/v1/explain` debug endpoint.
+    pub(crate) enable_explain: bool,
+    pub(crate) policies: policy::PolicyChain,
+    /// Token authorizing admin-only request parameters.
+    pub(crate) admin_token: Option<Secret>,
+}
+
+/// Secret value, redacted when debug-printed.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Secret(pub(crate) String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+/// Upstream metadata sources.
+#[derive(Clone, Debug)]
+pub(crate) struct UpstreamSettings {
+    pub(crate) streams: Vec<String>,
+    /// Architectures every release must have a payload for.
+    pub(crate) basearches: Vec<String>,
+    pub(crate) releases_url: String,
+    pub(crate) updates_url: String,
+    pub(crate) refresh_interval: Duration,
+    pub(crate) request_timeout: Duration,
+    pub(crate) max_retries: u32,
+    pub(crate) retry_initial_delay: Duration,
+    pub(crate) retry_max_delay: Duration,
+    pub(crate) state_dir: Option<PathBuf>,
+    /// Local file overriding rollout states.
+    pub(crate) rollout_overrides: Option<PathBuf>,
+    pub(crate) regression_checks: RegressionChecks,
+    /// Number of graph diffs kept in memory, per stream.
+    pub(crate) graph_diff_history: usize,
+}
+
+/// Safety checks for refreshed graphs, against the one being served.
+#[derive(Clone, Debug)]
+pub(crate) struct RegressionChecks {
+    /// Maximum drop in the number of releases, in percent.
+    pub(crate) max_release_drop_percent: u8,
+    /// Whether the latest release may disappear.
+    pub(crate) allow_latest_release_removal: bool,
+    /// Whether the set of barrier releases may change.
+    pub(crate) allow_barrier_changes: bool,
+}
+
+impl Settings {
+    /// Load settings from an optional config file, then apply environment overrides.
+    pub(crate) fn assemble(config_path: Option<&str>) -> Fallible<Self> {
+        let mut cfg = match config_path {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+        cfg.apply_env_overrides()?;
+        Self::validate(cfg)
+    }
+
+    /// Validate configuration fragments, filling in defaults.
+    fn validate(cfg: ConfigFile) -> Fallible<Self> {
+        let graph_builder = GraphBuilderSettings::validate(cfg.graph_builder)?;
+        let policy_engine = PolicyEngineSettings::validate(cfg.policy_engine)?;
+        let upstream = UpstreamSettings::validate(cfg.upstream)?;
+
+        let settings = Self {
+            graph_builder,
+            policy_engine,
+            upstream,
+        };
+        settings.check_port_conflicts()?;
+        for stream in settings.policy_engine.rollout_salts.keys() {
+            if !settings.upstream.streams.contains(stream) {
+                bail!(
+                    "invalid 'policy_engine.rollout_salts': unknown stream '{}'",
+                    stream
+                );
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Ensure that no two listeners share the same socket.
+    fn check_port_conflicts(&self) -> Fallible<()> {
+        let sockets = vec![
+            (
+                "graph_builder.port",
+                &self.graph_builder.service.address,
+                self.graph_builder.service.port,
+            ),
+            (
+                "graph_builder.status_port",
+                &self.graph_builder.service.address,
+                self.graph_builder.service.status_port,
+            ),
+            (
+                "policy_engine.port",
+                &self.policy_engine.service.address,
+                self.policy_engine.service.port,
+            ),
+            (
+                "policy_engine.status_port",
+                &self.policy_engine.service.address,
+                self.policy_engine.service.status_port,
+            ),
+        ];
+
+        let mut seen = HashMap::new();
+        for (name, addr, port) in sockets {
+            if let Some(other) = seen.insert(port, (name, addr)) {
+                if addr.is_unspecified() || other.1.is_unspecified() || addr == other.1 {
+                    bail!("'{}' and '{}' both use port {}", other.0, name, port);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceSettings {
+    fn validate(
+        section: &str,
+        address: Option<String>,
+        port: u16,
+        status_port: u16,
+    ) -> Fallible<Self> {
+        let address = match address {
+            Some(addr) => IpAddr::from_str(&addr)
+                .map_err(|e| format_err!("invalid '{}.address' '{}': {}", section, addr, e))?,
+            None => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        };
+        if port == 0 {
+            bail!("invalid '{}.port': must be non-zero", section);
+        }
+        if status_port == 0 {
+            bail!("invalid '{}.status_port': must be non-zero", section);
+        }
+
+        let settings = Self {
+            address,
+            port,
+            status_port,
+        };
+        Ok(settings)
+    }
+}
+
+impl GraphBuilderSettings {
+    fn validate(cfg: Option<GraphBuilderFragment>) -> Fallible<Self> {
+        let cfg = cfg.unwrap_or_default();
+        let service = ServiceSettings::validate(
+            "graph_builder",
+            cfg.address,
+            cfg.port.unwrap_or(DEFAULT_GB_PORT),
+            cfg.status_port.unwrap_or(DEFAULT_GB_STATUS_PORT),
+        )?;
+
+        let max_age_secs = cfg
+            .max_graph_age_secs
+            .unwrap_or(DEFAULT_GB_MAX_GRAPH_AGE_SECS);
+        let max_graph_age = if max_age_secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(max_age_secs))
+        };
+
+        let policies = validate_policies(
+            "graph_builder.policies",
+            cfg.policies,
+            policy::DEFAULT_GB_POLICIES,
+            policy::GB_ONLY_POLICIES,
+            &[],
+        )?;
+
+        let settings = Self {
+            service,
+            max_graph_age,
+            policies,
+        };
+        Ok(settings)
+    }
+}
+
+impl PolicyEngineSettings {
+    fn validate(cfg: Option<PolicyEngineFragment>) -> Fallible<Self> {
+        let cfg = cfg.unwrap_or_default();
+        let service = ServiceSettings::validate(
+            "policy_engine",
+            cfg.address,
+            cfg.port.unwrap_or(DEFAULT_PE_PORT),
+            cfg.status_port.unwrap_or(DEFAULT_PE_STATUS_PORT),
+        )?;
+
+        let graph_builder_url = Self::parse_base_url(
+            "policy_engine.graph_builder_url",
+            cfg.graph_builder_url,
+            DEFAULT_PE_GB_URL,
+        )?;
+        let graph_builder_status_url = Self::parse_base_url(
+            "policy_engine.graph_builder_status_url",
+            cfg.graph_builder_status_url,
+            DEFAULT_PE_GB_STATUS_URL,
+        )?;
+
+        let cache_secs = cfg.graph_cache_secs.unwrap_or(DEFAULT_PE_CACHE_SECS);
+
+        let mut rollout_salts = cfg.rollout_salts.unwrap_or_default();
+        // An empty salt is the same as no salt.
+        rollout_salts.retain(|_, salt| !salt.is_empty());
+
+        let policies = validate_policies(
+            "policy_engine.policies",
+            cfg.policies,
+            policy::DEFAULT_PE_POLICIES,
+            &[],
+            policy::GB_ONLY_POLICIES,
+        )?;
+
+        let settings = Self {
+            service,
+            graph_builder_url,
+            graph_builder_status_url,
+            graph_cache_ttl: Duration::from_secs(cache_secs),
+            rollout_salts,
+            enable_explain: cfg.enable_explain.unwrap_or(false),
+            policies,
+            admin_token: cfg.admin_token.filter(|t| !t.is_empty()).map(Secret),
+        };
+        Ok(settings)
+    }
+
+    /// Parse a base URL, falling back to `default` if unset.
+    fn parse_base_url(name: &str, input: Option<String>, default: &str) -> Fallible<reqwest::Url> {
+        let input = input.unwrap_or_else(|| default.to_string());
+        let url = reqwest::Url::parse(&input)
+            .map_err(|e| format_err!("invalid '{}' '{}': {}", name, input, e))?;
+        if url.cannot_be_a_base() {
+            bail!("invalid '{}' '{}': not a base URL", name, input);
+        }
+        Ok(url)
+    }
+}
+
+impl UpstreamSettings {
+    fn validate(cfg: Option<UpstreamFragment>) -> Fallible<Self> {
+        let cfg = cfg.unwrap_or_default();
+
+        let streams = cfg
+            .streams
+            .unwrap_or_else(|| DEFAULT_STREAMS.iter().map(|s| s.to_string()).collect());
+        if streams.is_empty() {
+            bail!("invalid 'upstream.streams': at least one stream is required");
+        }
+        for (index, stream) in streams.iter().enumerate() {
+            let valid_chars = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if stream.is_empty() || !stream.chars().all(valid_chars) {
+                bail!("invalid 'upstream.streams': bad stream name '{}'", stream);
+            }
+            if streams[..index].contains(stream) {
+                bail!("invalid 'upstream.streams': duplicate stream '{}'", stream);
+            }
+        }
+
+        let basearches = cfg
+            .basearches
+            .unwrap_or_else(|| DEFAULT_BASEARCHES.iter().map(|s| s.to_string()).collect());
+        for (index, basearch) in basearches.iter().enumerate() {
+            if !metadata::BASEARCHES.contains(&basearch.as_str()) {
+                bail!(
+                    "invalid 'upstream.basearches': unknown basearch '{}'",
+                    basearch
+                );
+            }
+            if basearches[..index].contains(basearch) {
+                bail!(
+                    "invalid 'upstream.basearches': duplicate basearch '{}'",
+                    basearch
+                );
+            }
+        }
+
+        let (releases_url, updates_url) = match cfg.source_dir {
+            Some(dir) => {
+                if cfg.releases_url.is_some() || cfg.updates_url.is_some() {
+                    bail!("'upstream.source_dir' conflicts with 'upstream.releases_url' and 'upstream.updates_url'");
+                }
+                Self::dir_templates(&dir)?
+            }
+            None => (
+                cfg.releases_url
+                    .unwrap_or_else(|| metadata::RELEASES_JSON.to_string()),
+                cfg.updates_url
+                    .unwrap_or_else(|| metadata::STREAM_JSON.to_string()),
+            ),
+        };
+        for (name, template) in &[
+            ("upstream.releases_url", &releases_url),
+            ("upstream.updates_url", &updates_url),
+        ] {
+            for stream in &streams {
+                Self::render_url(template, stream).map_err(|e| {
+                    format_err!(
+                        "invalid '{}' '{}' for stream '{}': {}",
+                        name,
+                        template,
+                        stream,
+                        e
+                    )
+                })?;
+            }
+        }
+
+        let refresh_secs = cfg.refresh_interval_secs.unwrap_or(DEFAULT_REFRESH_SECS);
+        if refresh_secs == 0 {
+            bail!("invalid 'upstream.refresh_interval_secs': must be non-zero");
+        }
+
+        let timeout_secs = cfg
+            .request_timeout_secs
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS);
+        if timeout_secs == 0 {
+            bail!("invalid 'upstream.request_timeout_secs': must be non-zero");
+        }
+        let max_retries = cfg.max_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+        let initial_delay_ms = cfg
+            .retry_initial_delay_ms
+            .unwrap_or(DEFAULT_RETRY_INITIAL_DELAY_MS);
+        let max_delay_ms = cfg.retry_max_delay_ms.unwrap_or(DEFAULT_RETRY_MAX_DELAY_MS);
+        if initial_delay_ms == 0 {
+            bail!("invalid 'upstream.retry_initial_delay_ms': must be non-zero");
+        }
+        if max_delay_ms < initial_delay_ms {
+            bail!(
+                "invalid 'upstream.retry_max_delay_ms': must not be lower than 'upstream.retry_initial_delay_ms' ({})",
+                initial_delay_ms
+            );
+        }
+
+        let state_dir = match cfg.state_dir {
+            Some(ref dir) if dir.is_empty() => None,
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => None,
+        };
+
+        let rollout_overrides = match cfg.rollout_overrides {
+            Some(ref path) if path.is_empty() => None,
+            Some(path) => Some(PathBuf::from(path)),
+            None => None,
+        };
+
+        let max_release_drop_percent = cfg
+            .max_release_drop_percent
+            .unwrap_or(DEFAULT_MAX_RELEASE_DROP_PERCENT);
+        if max_release_drop_percent > 100 {
+            bail!("invalid 'upstream.max_release_drop_percent': must not be greater than 100");
+        }
+        let regression_checks = RegressionChecks {
+            max_release_drop_percent,
+            allow_latest_release_removal: cfg.allow_latest_release_removal.unwrap_or(false),
+            allow_barrier_changes: cfg.allow_barrier_changes.unwrap_or(false),
+        };
+
+        let settings = Self {
+            streams,
+            basearches,
+            releases_url,
+            updates_url,
+            refresh_interval: Duration::from_secs(refresh_secs),
+            request_timeout: Duration::from_secs(timeout_secs),
+            max_retries,
+            retry_initial_delay: Duration::from_millis(initial_delay_ms),
+            retry_max_delay: Duration::from_millis(max_delay_ms),
+            state_dir,
+            rollout_overrides,
+            regression_checks,
+            graph_diff_history: cfg.graph_diff_history.unwrap_or(DEFAULT_GRAPH_DIFF_HISTORY),
+        };
+        Ok(settings)
+    }
+
+    /// Render a templated upstream URL for the given stream.
+    pub(crate) fn render_url(template: &str, stream: &str) -> Fallible<reqwest::Url> {
+        let vars = hashmap! { "stream".to_string() => stream.to_string() };
+        let rendered = envsubst::substitute(template, &vars)
+            .map_err(|e| format_err!("failed to render template: {}", e))?;
+        let url = reqwest::Url::parse(&rendered)?;
+        match url.scheme() {
+            "http" | "https" | "file" => {}
+            scheme => bail!("unsupported URL scheme '{}'", scheme),
+        };
+        Ok(url)
+    }
+
+    /// Build `file://` URL templates for a local metadata directory.
+    ///
+    /// The directory mirrors the upstream layout, i.e. it contains
+    /// `streams/<stream>/releases.json` and `updates/<stream>.json`.
+    fn dir_templates(dir: &str) -> Fallible<(String, String)> {
+        let path = std::fs::canonicalize(dir)
+            .map_err(|e| format_err!("invalid 'upstream.source_dir' '{}': {}", dir, e))?;
+        let base = reqwest::Url::from_directory_path(&path)
+            .map_err(|_| format_err!("invalid 'upstream.source_dir' '{}'", dir))?;
+        let releases = format!("{}streams/${{stream}}/releases.json", base);
+        let updates = format!("{}updates/${{stream}}.json", base);
+        Ok((releases, updates))
+    }
+}
+
+/// Build a policy chain from configured names, falling back to `default` if unset.
+///
+/// Policies listed in `required` must be part of the chain, and those listed in
+/// `unsupported` cannot.
+fn validate_policies(
+    name: &str,
+    input: Option<Vec<String>>,
+    default: &[&str],
+    required: &[&str],
+    unsupported: &[&str],
+) -> Fallible<policy::PolicyChain> {
+    let names = input.unwrap_or_else(|| default.iter().map(|s| s.to_string()).collect());
+    for policy in required {
+        if !names.iter().any(|n| n == policy) {
+            bail!("invalid '{}': missing required policy '{}'", name, policy);
+        }
+    }
+    for (index, policy) in names.iter().enumerate() {
+        if names[..index].contains(policy) {
+            bail!("invalid '{}': duplicate policy '{}'", name, policy);
+        }
+        if unsupported.contains(&policy.as_str()) {
+            bail!("invalid '{}': unsupported policy '{}'", name, policy);
+        }
+    }
+    policy::PolicyChain::from_names(&names).map_err(|e| format_err!("invalid '{}': {}", name, e))
+}
+
+/// Top-level TOML configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    graph_builder: Option<GraphBuilderFragment>,
+    policy_engine: Option<PolicyEngineFragment>,
+    upstream: Option<UpstreamFragment>,
+}
+
+/// TOML fragment for the `graph_builder` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GraphBuilderFragment {
+    address: Option<String>,
+    port: Option<u16>,
+    status_port: Option<u16>,
+    max_graph_age_secs: Option<u64>,
+    policies: Option<Vec<String>>,
+}
+
+/// TOML fragment for the `policy_engine` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyEngineFragment {
+    address: Option<String>,
+    port: Option<u16>,
+    status_port: Option<u16>,
+    graph_builder_url: Option<String>,
+    graph_builder_status_url: Option<String>,
+    graph_cache_secs: Option<u64>,
+    rollout_salts: Option<HashMap<String, String>>,
+    enable_explain: Option<bool>,
+    policies: Option<Vec<String>>,
+    admin_token: Option<String>,
+}
+
+/// TOML fragment for the `upstream` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamFragment {
+    streams: Option<Vec<String>>,
+    basearches: Option<Vec<String>>,
+    releases_url: Option<String>,
+    updates_url: Option<String>,
+    source_dir: Option<String>,
+    refresh_interval_secs: Option<u64>,
+    request_timeout_secs: Option<u64>,
+    max_retries: Option<u32>,
+    retry_initial_delay_ms: Option<u64>,
+    retry_max_delay_ms: Option<u64>,
+    state_dir: Option<String>,
+    rollout_overrides: Option<String>,
+    max_release_drop_percent: Option<u8>,
+    allow_latest_release_removal: Option<bool>,
+    allow_barrier_changes: Option<bool>,
+    graph_diff_history: Option<usize>,
+}
+
+impl ConfigFile {
+    /// Read and parse a TOML configuration file.
+    fn read<P: AsRef<Path>>(path: P) -> Fallible<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read config '{}': {}", path.display(), e))?;
+        let cfg = toml::from_str(&content)
+            .map_err(|e| format_err!("failed to parse config '{}': {}", path.display(), e))?;
+        Ok(cfg)
+    }
+
+    /// Override configuration values from `DUMNATI_*` environment variables.
+    fn apply_env_overrides(&mut self) -> Fallible<()> {
+        let gb = self.graph_builder.get_or_insert_with(Default::default);
+        env_override("DUMNATI_GRAPH_BUILDER_ADDRESS", &mut gb.address)?;
+        env_override("DUMNATI_GRAPH_BUILDER_PORT", &mut gb.port)?;
+        env_override("DUMNATI_GRAPH_BUILDER_STATUS_PORT", &mut gb.status_port)?;
+        env_override(
+            "DUMNATI_GRAPH_BUILDER_MAX_GRAPH_AGE_SECS",
+            &mut gb.max_graph_age_secs,
+        )?;
+        env_override_list("DUMNATI_GRAPH_BUILDER_POLICIES", &mut gb.policies)?;
+
+        let pe = self.policy_engine.get_or_insert_with(Default::default);
+        env_override("DUMNATI_POLICY_ENGINE_ADDRESS", &mut pe.address)?;
+        env_override("DUMNATI_POLICY_ENGINE_PORT", &mut pe.port)?;
+        env_override("DUMNATI_POLICY_ENGINE_STATUS_PORT", &mut pe.status_port)?;
+        env_override(
+            "DUMNATI_POLICY_ENGINE_GRAPH_BUILDER_URL",
+            &mut pe.graph_builder_url,
+        )?;
+        env_override(
+            "DUMNATI_POLICY_ENGINE_GRAPH_BUILDER_STATUS_URL",
+            &mut pe.graph_builder_status_url,
+        )?;
+        env_override(
+            "DUMNATI_POLICY_ENGINE_GRAPH_CACHE_SECS",
+            &mut pe.graph_cache_secs,
+        )?;
+        env_override_map("DUMNATI_POLICY_ENGINE_ROLLOUT_SALTS", &mut pe.rollout_salts)?;
+        env_override(
+            "DUMNATI_POLICY_ENGINE_ENABLE_EXPLAIN",
+            &mut pe.enable_explain,
+        )?;
+        env_override_list("DUMNATI_POLICY_ENGINE_POLICIES", &mut pe.policies)?;
+        env_override("DUMNATI_POLICY_ENGINE_ADMIN_TOKEN", &mut pe.admin_token)?;
+
+        let upstream = self.upstream.get_or_insert_with(Default::default);
+        env_override_list("DUMNATI_UPSTREAM_STREAMS", &mut upstream.streams)?;
+        env_override_list("DUMNATI_UPSTREAM_BASEARCHES", &mut upstream.basearches)?;
+        env_override("DUMNATI_UPSTREAM_RELEASES_URL", &mut upstream.releases_url)?;
+        env_override("DUMNATI_UPSTREAM_UPDATES_URL", &mut upstream.updates_url)?;
+        env_override("DUMNATI_UPSTREAM_SOURCE_DIR", &mut upstream.source_dir)?;
+        env_override(
+            "DUMNATI_UPSTREAM_REFRESH_INTERVAL_SECS",
+            &mut upstream.refresh_interval_secs,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_REQUEST_TIMEOUT_SECS",
+            &mut upstream.request_timeout_secs,
+        )?;
+        env_override("DUMNATI_UPSTREAM_MAX_RETRIES", &mut upstream.max_retries)?;
+        env_override(
+            "DUMNATI_UPSTREAM_RETRY_INITIAL_DELAY_MS",
+            &mut upstream.retry_initial_delay_ms,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_RETRY_MAX_DELAY_MS",
+            &mut upstream.retry_max_delay_ms,
+        )?;
+        env_override("DUMNATI_UPSTREAM_STATE_DIR", &mut upstream.state_dir)?;
+        env_override(
+            "DUMNATI_UPSTREAM_ROLLOUT_OVERRIDES",
+            &mut upstream.rollout_overrides,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_MAX_RELEASE_DROP_PERCENT",
+            &mut upstream.max_release_drop_percent,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_ALLOW_LATEST_RELEASE_REMOVAL",
+            &mut upstream.allow_latest_release_removal,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_ALLOW_BARRIER_CHANGES",
+            &mut upstream.allow_barrier_changes,
+        )?;
+        env_override(
+            "DUMNATI_UPSTREAM_GRAPH_DIFF_HISTORY",
+            &mut upstream.graph_diff_history,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Replace `value` with the content of environment variable `name`, if set.
+fn env_override<T>(name: &str, value: &mut Option<T>) -> Fallible<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let raw = match std::env::var(name) {
+        Ok(raw) => raw,
+        Err(std::env::VarError::NotPresent) => return Ok(()),
+        Err(e) => bail!("invalid environment variable '{}': {}", name, e),
+    };
+    let parsed = raw
+        .parse::<T>()
+        .map_err(|e| format_err!("invalid environment variable '{}': {}", name, e))?;
+    *value = Some(parsed);
+    Ok(())
+}
+
+/// Replace `value` with the comma-separated content of environment variable `name`, if set.
+fn env_override_list(name: &str, value: &mut Option<Vec<String>>) -> Fallible<()> {
+    let mut raw: Option<String> = None;
+    env_override(name, &mut raw)?;
+    if let Some(list) = raw {
+        let entries = list
+            .split(',')
+            .map(|entry| entry.trim().to_string())
+            .collect();
+        *value = Some(entries);
+    }
+    Ok(())
+}
+
+/// Replace `value` with the comma-separated `key=value` pairs of environment variable `name`, if set.
+fn env_override_map(name: &str, value: &mut Option<HashMap<String, String>>) -> Fallible<()> {
+    let mut raw: Option<Vec<String>> = None;
+    env_override_list(name, &mut raw)?;
+    if let Some(list) = raw {
+        let mut entries = HashMap::with_capacity(list.len());
+        for entry in list.iter().filter(|e| !e.is_empty()) {
+            let mut kv = entry.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(val)) => {
+                    entries.insert(key.trim().to_string(), val.trim().to_string())
+                }
+                _ => bail!(
+                    "invalid environment variable '{}': bad entry '{}'",
+                    name,
+                    entry
+                ),
+            };
+        }
+        *value = Some(entries);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse and validate TOML configuration, ignoring the environment.
+    fn parse(content: &str) -> Fallible<Settings> {
+        let cfg: ConfigFile = toml::from_str(content)?;
+        Settings::validate(cfg)
+    }
+
+    #[test]
+    fn settings_from_toml() {
+        let defaults = parse("").unwrap();
+        assert_eq!(defaults.graph_builder.service.port, DEFAULT_GB_PORT);
+        assert_eq!(defaults.policy_engine.service.port, DEFAULT_PE_PORT);
+        assert_eq!(defaults.upstream.streams, DEFAULT_STREAMS);
+        assert_eq!(defaults.upstream.basearches, DEFAULT_BASEARCHES);
+        assert_eq!(defaults.upstream.releases_url, metadata::RELEASES_JSON);
+        assert_eq!(defaults.upstream.state_dir, None);
+
+        let settings = parse(
+            r#"
+            [graph_builder]
+            address = "127.0.0.1"
+            port = 8180
+            max_graph_age_secs = 0
+
+            [policy_engine]
+            graph_builder_url = "http://gb.example.com:8180"
+            rollout_salts = { testing = "salt", stable = "" }
+
+            [upstream]
+            streams = ["testing"]
+            basearches = ["x86_64", "aarch64"]
+            updates_url = "https://example.com/${stream}/updates.json"
+            refresh_interval_secs = 5
+            state_dir = ""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.graph_builder.service.address,
+            IpAddr::from(Ipv4Addr::LOCALHOST)
+        );
+        assert_eq!(settings.graph_builder.service.port, 8180);
+        assert_eq!(settings.graph_builder.max_graph_age, None);
+        assert_eq!(
+            settings.policy_engine.graph_builder_url.as_str(),
+            "http://gb.example.com:8180/"
+        );
+        assert_eq!(
+            settings.policy_engine.rollout_salts,
+            hashmap! { "testing".to_string() => "salt".to_string() }
+        );
+        assert_eq!(settings.upstream.streams, vec!["testing"]);
+        assert_eq!(settings.upstream.basearches, vec!["x86_64", "aarch64"]);
+        assert_eq!(
+            UpstreamSettings::render_url(&settings.upstream.updates_url, "testing")
+                .unwrap()
+                .as_str(),
+            "https://example.com/testing/updates.json"
+        );
+        assert_eq!(settings.upstream.refresh_interval, Duration::from_secs(5));
+        assert_eq!(settings.upstream.state_dir, None);
+
+        let invalid = [
+            "[graph_builder]\nunknown_key = 1",
+            "[graph_builder]\naddress = \"localhost\"",
+            "[policy_engine]\nport = 9080",
+            "[policy_engine]\nrollout_salts = { unknown = \"salt\" }",
+            "[upstream]\nstreams = []",
+            "[upstream]\nstreams = [\"test/ing\"]",
+            "[upstream]\nstreams = [\"testing\", \"testing\"]",
+            "[upstream]\nbasearches = [\"i686\"]",
+            "[upstream]\nbasearches = [\"x86_64\", \"x86_64\"]",
+            "[upstream]\nreleases_url = \"ftp://example.com/${stream}\"",
+            "[upstream]\nrefresh_interval_secs = 0",
+            "[upstream]\nretry_initial_delay_ms = 500\nretry_max_delay_ms = 100",
+        ];
+        for content in &invalid {
+            assert!(parse(content).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn env_overrides() {
+        std::env::set_var("DUMNATI_TEST_ENV_PORT", "8180");
+        std::env::set_var("DUMNATI_TEST_ENV_BAD_PORT", "http");
+        std::env::set_var("DUMNATI_TEST_ENV_LIST", "stable, testing");
+        std::env::set_var("DUMNATI_TEST_ENV_MAP", "stable=a, testing = b=c,");
+        std::env::set_var("DUMNATI_TEST_ENV_BAD_MAP", "stable");
+
+        let mut port: Option<u16> = Some(8080);
+        env_override("DUMNATI_TEST_ENV_UNSET", &mut port).unwrap();
+        assert_eq!(port, Some(8080));
+        env_override("DUMNATI_TEST_ENV_PORT", &mut port).unwrap();
+        assert_eq!(port, Some(8180));
+        env_override("DUMNATI_TEST_ENV_BAD_PORT", &mut port).unwrap_err();
+
+        let mut streams = None;
+        env_override_list("DUMNATI_TEST_ENV_LIST", &mut streams).unwrap();
+        assert_eq!(streams.unwrap(), vec!["stable", "testing"]);
+
+        let mut salts = None;
+        env_override_map("DUMNATI_TEST_ENV_MAP", &mut salts).unwrap();
+        assert_eq!(
+            salts.unwrap(),
+            hashmap! {
+                "stable".to_string() => "a".to_string(),
+                "testing".to_string() => "b=c".to_string(),
+            }
+        );
+        env_override_map("DUMNATI_TEST_ENV_BAD_MAP", &mut None).unwrap_err();
+    }
+
+    #[test]
+    fn policy_chains_validation() {
+        let chain = validate_policies("test", None, policy::DEFAULT_PE_POLICIES, &[], &[]).unwrap();
+        assert_eq!(chain.to_string(), "[throttle_rollouts, trim_to_reachable]");
+
+        let names = vec!["filter_deadends".to_string()];
+        let chain = validate_policies("test", Some(names), &[], &[], &[]).unwrap();
+        assert_eq!(chain.to_string(), "[filter_deadends]");
+
+        let duplicated = vec!["filter_deadends".to_string(), "filter_deadends".to_string()];
+        validate_policies("test", Some(duplicated), &[], &[], &[]).unwrap_err();
+        let unknown = vec!["unknown".to_string()];
+        validate_policies("test", Some(unknown), &[], &[], &[]).unwrap_err();
+
+        let input = r#"
+            [policy_engine]
+            policies = ["pick_basearch", "trim_to_reachable"]
+        "#;
+        let err = parse(input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid 'policy_engine.policies': unsupported policy 'pick_basearch'"
+        );
+        let input = r#"
+            [graph_builder]
+            policies = ["pick_basearch"]
+        "#;
+        parse(input).unwrap();
+        let input = r#"
+            [graph_builder]
+            policies = ["filter_deadends"]
+        "#;
+        let err = parse(input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid 'graph_builder.policies': missing required policy 'pick_basearch'"
+        );
+    }
+
+    #[test]
+    fn source_dir_templates() {
+        let dir = std::env::temp_dir().join(format!("dumnati-source-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = format!("[upstream]\nsource_dir = \"{}\"", dir.display());
+        let settings = parse(&input).unwrap();
+        let render = |template: &str| {
+            let url = UpstreamSettings::render_url(template, "testing").unwrap();
+            url.to_file_path().unwrap()
+        };
+        let base = std::fs::canonicalize(&dir).unwrap();
+        assert_eq!(
+            render(&settings.upstream.releases_url),
+            base.join("streams/testing/releases.json")
+        );
+        assert_eq!(
+            render(&settings.upstream.updates_url),
+            base.join("updates/testing.json")
+        );
+
+        let conflicting = format!("{}\nupdates_url = \"https://example.com/\"", input);
+        let err = parse(&conflicting).unwrap_err();
+        assert!(err.to_string().contains("conflicts"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let err = parse(&input).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid 'upstream.source_dir'"),
+            "{}",
+            err
+        );
+    }
+}
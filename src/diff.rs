@@ -0,0 +1,227 @@
+//! Structured differences between graphs.
+//!
+//! The scraper computes a diff each time it swaps in a new graph, so that
+//! changes to the served graph can be audited after the fact.
+
+use crate::graph::{CincinnatiPayload, Graph};
+use crate::metadata;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Changes between two versions of a graph.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct GraphDiff {
+    /// When the new graph was swapped in.
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) added_releases: Vec<String>,
+    pub(crate) removed_releases: Vec<String>,
+    pub(crate) added_edges: Vec<Edge>,
+    pub(crate) removed_edges: Vec<Edge>,
+    pub(crate) changed_metadata: Vec<MetadataChange>,
+}
+
+/// Update edge, by release versions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub(crate) struct Edge {
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+/// Change to a metadata entry of a release present in both graphs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct MetadataChange {
+    pub(crate) version: String,
+    pub(crate) key: String,
+    pub(crate) old: Option<String>,
+    pub(crate) new: Option<String>,
+}
+
+impl GraphDiff {
+    /// Compute changes from `previous` to `next`.
+    pub(crate) fn compute(previous: &Graph, next: &Graph, timestamp: DateTime<Utc>) -> Self {
+        let old_nodes: HashMap<_, _> = previous.nodes.iter().map(|n| (&n.version, n)).collect();
+        let new_nodes: HashMap<_, _> = next.nodes.iter().map(|n| (&n.version, n)).collect();
+
+        let added_releases = next
+            .nodes
+            .iter()
+            .filter(|n| !old_nodes.contains_key(&n.version))
+            .map(|n| n.version.clone())
+            .collect();
+        let removed_releases = previous
+            .nodes
+            .iter()
+            .filter(|n| !new_nodes.contains_key(&n.version))
+            .map(|n| n.version.clone())
+            .collect();
+
+        let old_edges = edges(previous);
+        let new_edges = edges(next);
+        let old_set: HashSet<_> = old_edges.iter().collect();
+        let new_set: HashSet<_> = new_edges.iter().collect();
+        let added_edges = new_edges
+            .iter()
+            .filter(|e| !old_set.contains(e))
+            .cloned()
+            .collect();
+        let removed_edges = old_edges
+            .iter()
+            .filter(|e| !new_set.contains(e))
+            .cloned()
+            .collect();
+
+        let mut changed_metadata = vec![];
+        for node in &next.nodes {
+            if let Some(old) = old_nodes.get(&node.version) {
+                changed_metadata.extend(metadata_changes(old, node));
+            }
+        }
+
+        Self {
+            timestamp,
+            added_releases,
+            removed_releases,
+            added_edges,
+            removed_edges,
+            changed_metadata,
+        }
+    }
+
+    /// Whether the two graphs are the same.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added_releases.is_empty()
+            && self.removed_releases.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+            && self.changed_metadata.is_empty()
+    }
+}
+
+impl std::fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added and {} removed releases, {} added and {} removed edges, {} metadata changes",
+            self.added_releases.len(),
+            self.removed_releases.len(),
+            self.added_edges.len(),
+            self.removed_edges.len(),
+            self.changed_metadata.len()
+        )
+    }
+}
+
+impl std::fmt::Display for Edge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.from, self.to)
+    }
+}
+
+impl std::fmt::Display for MetadataChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let show = |value: &Option<String>| match value {
+            Some(v) => format!("'{}'", v),
+            None => "unset".to_string(),
+        };
+        write!(
+            f,
+            "release '{}': {} changed from {} to {}",
+            self.version,
+            self.key,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// Return graph edges by release versions.
+fn edges(graph: &Graph) -> Vec<Edge> {
+    graph
+        .edges
+        .iter()
+        .map(|(from, to)| Edge {
+            from: graph.nodes[*from as usize].version.clone(),
+            to: graph.nodes[*to as usize].version.clone(),
+        })
+        .collect()
+}
+
+/// Return metadata changes for a release, sorted by key.
+///
+/// Age indices are ignored, as they shift whenever older releases come and go.
+fn metadata_changes(old: &CincinnatiPayload, new: &CincinnatiPayload) -> Vec<MetadataChange> {
+    let keys: BTreeSet<_> = old
+        .metadata
+        .keys()
+        .chain(new.metadata.keys())
+        .filter(|k| k.as_str() != metadata::AGE_INDEX)
+        .collect();
+    keys.into_iter()
+        .filter_map(|key| {
+            let (before, after) = (old.metadata.get(key), new.metadata.get(key));
+            if before == after {
+                return None;
+            }
+            Some(MetadataChange {
+                version: new.version.clone(),
+                key: key.clone(),
+                old: before.cloned(),
+                new: after.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn graph_diff() {
+        let previous = Graph::from_metadata(
+            metadata::test_releases(&["30.0", "30.1", "30.2"]),
+            serde_json::from_value(serde_json::json!({
+                "stream": "testing",
+                "releases": [],
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let next = Graph::from_metadata(
+            metadata::test_releases(&["30.1", "30.2", "30.3"]),
+            serde_json::from_value(serde_json::json!({
+                "stream": "testing",
+                "releases": [
+                    { "version": "30.2", "metadata": { "barrier": { "reason": "" } } },
+                ],
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let now = Utc.timestamp(1_570_000_000, 0);
+
+        assert!(GraphDiff::compute(&previous, &previous, now).is_empty());
+
+        let diff = GraphDiff::compute(&previous, &next, now);
+        assert_eq!(diff.added_releases, vec!["30.3"]);
+        assert_eq!(diff.removed_releases, vec!["30.0"]);
+        let added: Vec<_> = diff.added_edges.iter().map(|e| e.to_string()).collect();
+        assert_eq!(added, vec!["30.2 -> 30.3"]);
+        let removed: Vec<_> = diff.removed_edges.iter().map(|e| e.to_string()).collect();
+        assert_eq!(removed, vec!["30.0 -> 30.1", "30.0 -> 30.2"]);
+        let changed: Vec<_> = diff
+            .changed_metadata
+            .iter()
+            .map(|c| (c.version.as_str(), c.key.as_str()))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                ("30.2", metadata::BARRIER),
+                ("30.2", metadata::BARRIER_REASON),
+            ]
+        );
+    }
+}
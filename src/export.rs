@@ -0,0 +1,227 @@
+//! Human-readable export of update graphs.
+//!
+//! Graphs can be rendered as Graphviz DOT or Mermaid flowcharts, with releases
+//! labelled by version and update markers, and edges colored by type.
+
+use crate::graph::{CincinnatiPayload, Graph};
+use crate::{config, metadata, upstream};
+use failure::{bail, Fallible};
+use std::fmt::Write;
+use structopt::StructOpt;
+
+/// Options for the `export` subcommand.
+#[derive(Debug, StructOpt)]
+pub(crate) struct ExportOptions {
+    #[structopt(flatten)]
+    pub sources: upstream::SourceOptions,
+    /// Output format (json, dot or mermaid).
+    #[structopt(long = "format", default_value = "dot")]
+    pub format: Format,
+}
+
+/// Export the graph for a stream, printing it to stdout.
+pub(crate) fn run(settings: config::Settings, opts: ExportOptions) -> Fallible<()> {
+    let (releases, updates) = opts.sources.load(&settings.upstream)?;
+    let graph = Graph::from_metadata(releases.releases, updates)?;
+    let output = opts.format.render(&graph, &opts.sources.stream)?;
+    print!("{}", output);
+    if !output.ends_with('\n') {
+        println!();
+    }
+    Ok(())
+}
+
+/// Export format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Dot,
+    Mermaid,
+}
+
+impl std::str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let format = match input {
+            "json" => Format::Json,
+            "dot" => Format::Dot,
+            "mermaid" => Format::Mermaid,
+            _ => bail!("unknown format '{}' (expected json, dot or mermaid)", input),
+        };
+        Ok(format)
+    }
+}
+
+impl Format {
+    /// Media type of rendered graphs.
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Dot => "text/vnd.graphviz",
+            Format::Mermaid => "text/plain",
+        }
+    }
+
+    /// Render `graph`, titled after `stream`.
+    pub(crate) fn render(self, graph: &Graph, stream: &str) -> Fallible<String> {
+        let output = match self {
+            Format::Json => serde_json::to_string_pretty(graph)?,
+            Format::Dot => to_dot(graph, stream),
+            Format::Mermaid => to_mermaid(graph),
+        };
+        Ok(output)
+    }
+}
+
+/// Type of an update edge, by the markers of its endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EdgeKind {
+    /// Update from a dead-end release.
+    Deadend,
+    /// Update to a barrier release.
+    Barrier,
+    /// Update to a release being rolled out.
+    Rollout,
+    /// Any other update.
+    Regular,
+}
+
+impl EdgeKind {
+    fn of(graph: &Graph, (from, to): (u64, u64)) -> Self {
+        let (from, to) = (&graph.nodes[from as usize], &graph.nodes[to as usize]);
+        if from.metadata.contains_key(metadata::DEADEND) {
+            EdgeKind::Deadend
+        } else if to.metadata.contains_key(metadata::BARRIER) {
+            EdgeKind::Barrier
+        } else if to.metadata.contains_key(metadata::ROLLOUT) {
+            EdgeKind::Rollout
+        } else {
+            EdgeKind::Regular
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            EdgeKind::Deadend => "#cc0000",
+            EdgeKind::Barrier => "#e69500",
+            EdgeKind::Rollout => "#1f6fd1",
+            EdgeKind::Regular => "#555555",
+        }
+    }
+}
+
+/// Return the update markers of a release.
+fn markers(node: &CincinnatiPayload) -> Vec<&'static str> {
+    let mut markers = vec![];
+    if node.metadata.contains_key(metadata::BARRIER) {
+        markers.push("barrier");
+    }
+    if node.metadata.contains_key(metadata::DEADEND) {
+        markers.push("deadend");
+    }
+    if node.metadata.contains_key(metadata::ROLLOUT_ABORTED) {
+        markers.push("rollout (aborted)");
+    } else if node.metadata.contains_key(metadata::ROLLOUT_PAUSED) {
+        markers.push("rollout (paused)");
+    } else if node.metadata.contains_key(metadata::ROLLOUT) {
+        markers.push("rollout");
+    }
+    markers
+}
+
+/// Render a graph in Graphviz DOT format.
+fn to_dot(graph: &Graph, stream: &str) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", escape(stream)).unwrap();
+    writeln!(out, "  rankdir=LR;").unwrap();
+    writeln!(out, "  node [shape=box];").unwrap();
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let mut label = escape(&node.version);
+        for marker in markers(node) {
+            label.push_str("\\n");
+            label.push_str(marker);
+        }
+        writeln!(out, "  n{} [label=\"{}\"];", index, label).unwrap();
+    }
+    for &(from, to) in &graph.edges {
+        let color = EdgeKind::of(graph, (from, to)).color();
+        writeln!(out, "  n{} -> n{} [color=\"{}\"];", from, to, color).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Render a graph as a Mermaid flowchart.
+fn to_mermaid(graph: &Graph) -> String {
+    let escape = |s: &str| s.replace('"', "#quot;");
+
+    let mut out = String::new();
+    writeln!(out, "graph LR").unwrap();
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let mut label = escape(&node.version);
+        for marker in markers(node) {
+            label.push_str("<br/>");
+            label.push_str(marker);
+        }
+        writeln!(out, "  n{}[\"{}\"]", index, label).unwrap();
+    }
+    for &(from, to) in &graph.edges {
+        writeln!(out, "  n{} --> n{}", from, to).unwrap();
+    }
+    for (index, &edge) in graph.edges.iter().enumerate() {
+        let color = EdgeKind::of(graph, edge).color();
+        writeln!(out, "  linkStyle {} stroke:{}", index, color).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_formats() {
+        let releases = metadata::test_releases(&["30.0", "30.1", "30.2"]);
+        let updates = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [
+                { "version": "30.1", "metadata": { "barrier": { "reason": "" } } },
+                { "version": "30.2", "metadata": { "rollout": {
+                    "start_epoch": 1_570_000_000, "start_percentage": 0.0, "duration_minutes": 60,
+                } } },
+            ],
+        }))
+        .unwrap();
+        let graph = Graph::from_metadata(releases, updates).unwrap();
+
+        let dot = Format::Dot.render(&graph, "testing").unwrap();
+        let expected = r##"digraph "testing" {
+  rankdir=LR;
+  node [shape=box];
+  n0 [label="30.0"];
+  n1 [label="30.1\nbarrier"];
+  n2 [label="30.2\nrollout"];
+  n0 -> n1 [color="#e69500"];
+  n1 -> n2 [color="#1f6fd1"];
+}
+"##;
+        assert_eq!(dot, expected);
+
+        let mermaid = Format::Mermaid.render(&graph, "testing").unwrap();
+        let expected = r##"graph LR
+  n0["30.0"]
+  n1["30.1<br/>barrier"]
+  n2["30.2<br/>rollout"]
+  n0 --> n1
+  n1 --> n2
+  linkStyle 0 stroke:#e69500
+  linkStyle 1 stroke:#1f6fd1
+"##;
+        assert_eq!(mermaid, expected);
+
+        assert!("svg".parse::<Format>().is_err());
+    }
+}
@@ -10,27 +10,18 @@ pub(crate) struct CincinnatiPayload {
     pub(crate) payload: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Graph {
     pub(crate) nodes: Vec<CincinnatiPayload>,
     pub(crate) edges: Vec<(u64, u64)>,
 }
 
-impl Default for Graph {
-    fn default() -> Self {
-        Self {
-            nodes: vec![],
-            edges: vec![],
-        }
-    }
-}
-
 impl Graph {
     pub fn from_metadata(
         releases: Vec<metadata::Release>,
         updates: metadata::UpdatesJSON,
     ) -> Fallible<Self> {
-        let nodes = releases
+        let nodes: Vec<CincinnatiPayload> = releases
             .into_iter()
             .enumerate()
             .map(|(age_index, entry)| {
@@ -70,42 +61,135 @@ impl Graph {
         Ok(graph)
     }
 
-    fn compute_edges(nodes: &Vec<CincinnatiPayload>) -> Fallible<Vec<(u64, u64)>> {
-        use std::collections::BTreeSet;
-
-        // Collect all rollouts and barriers.
-        let mut rollouts = BTreeSet::<u64>::new();
-        let mut barriers = BTreeSet::<u64>::new();
-        for (index, release) in nodes.iter().enumerate() {
-            if release.metadata.contains_key(metadata::ROLLOUT) {
-                rollouts.insert(index as u64);
+    /// Apply local rollout state overrides, keyed by release version.
+    ///
+    /// Overrides only affect releases which are being rolled out.
+    pub fn apply_rollout_overrides(&mut self, overrides: &HashMap<String, String>) {
+        for release in &mut self.nodes {
+            let state = match overrides.get(&release.version) {
+                Some(state) => state.as_str(),
+                None => continue,
+            };
+            if !release.metadata.contains_key(metadata::ROLLOUT) {
+                log::warn!(
+                    "ignoring rollout override for '{}': not being rolled out",
+                    release.version
+                );
+                continue;
             }
-            if release.metadata.contains_key(metadata::BARRIER) {
-                barriers.insert(index as u64);
+            match state {
+                metadata::STATE_PAUSED => {
+                    release
+                        .metadata
+                        .entry(metadata::ROLLOUT_PAUSED.to_string())
+                        .or_default();
+                }
+                metadata::STATE_ABORTED => {
+                    release
+                        .metadata
+                        .insert(metadata::ROLLOUT_ABORTED.to_string(), true.to_string());
+                }
+                metadata::STATE_ACTIVE => {
+                    release.metadata.remove(metadata::ROLLOUT_PAUSED);
+                    release.metadata.remove(metadata::ROLLOUT_ABORTED);
+                }
+                _ => {}
             }
         }
+    }
 
-        // Add edges targeting rollouts, back till the last barrier.
-        let mut edges = vec![];
-        for (index, _release) in nodes.iter().enumerate().rev() {
-            let age = index as u64;
-            if !rollouts.contains(&age) {
-                continue;
-            }
+    /// Pin rollouts paused at an unknown time to when the pause was first
+    /// observed, i.e. in the `previous` graph if already paused there, or `now`.
+    pub fn pin_paused_rollouts(&mut self, previous: &Graph, now: i64) {
+        for release in &mut self.nodes {
+            match release.metadata.get(metadata::ROLLOUT_PAUSED) {
+                Some(epoch) if epoch.is_empty() => {}
+                _ => continue,
+            };
+            let pinned = previous
+                .nodes
+                .iter()
+                .find(|prev| prev.version == release.version)
+                .and_then(|prev| prev.metadata.get(metadata::ROLLOUT_PAUSED))
+                .filter(|epoch| !epoch.is_empty())
+                .cloned()
+                .unwrap_or_else(|| now.to_string());
+            release
+                .metadata
+                .insert(metadata::ROLLOUT_PAUSED.to_string(), pinned);
+        }
+    }
+
+    /// Compute the recommended update path from the node at index `from`.
+    ///
+    /// The path leads to the newest release reachable from `from`, going
+    /// through the minimum number of hops (i.e. only mandatory barriers,
+    /// as long as edges are not pruned). It starts with `from` itself, and
+    /// is empty if `from` is not in the graph.
+    pub fn update_path(&self, from: usize) -> Vec<usize> {
+        use std::collections::hash_map::Entry;
+        use std::collections::{BTreeMap, VecDeque};
+
+        if from >= self.nodes.len() {
+            return vec![];
+        }
 
-            let last_barrier = barriers.iter().last().cloned().unwrap_or(0);
-            for i in last_barrier..age {
-                edges.push((i, age))
+        let mut successors: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (source, target) in &self.edges {
+            successors
+                .entry(*source as usize)
+                .or_default()
+                .push(*target as usize);
+        }
+
+        // Breadth-first visit, recording how each node was first reached.
+        let mut previous = HashMap::new();
+        let mut pending = VecDeque::new();
+        pending.push_back(from);
+        previous.insert(from, from);
+        let mut newest = from;
+        while let Some(index) = pending.pop_front() {
+            newest = newest.max(index);
+            let mut next = successors.remove(&index).unwrap_or_default();
+            next.sort();
+            for target in next {
+                if let Entry::Vacant(entry) = previous.entry(target) {
+                    entry.insert(index);
+                    pending.push_back(target);
+                }
             }
         }
 
-        // Add edges targeting barriers, back till the previous barrier.
-        let mut start = 0;
-        for target in barriers {
-            for i in start..target {
-                edges.push((i, target))
+        let mut path = vec![newest];
+        let mut current = newest;
+        while current != from {
+            current = previous[&current];
+            path.push(current);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Compute update edges between releases.
+    ///
+    /// Releases are ordered by age index, oldest first. Each release can be
+    /// reached from every older release, going back to (and including) the
+    /// nearest preceding barrier. Barriers are thus mandatory hops, as no edge
+    /// ever crosses them.
+    ///
+    /// Rollout and dead-end markers do not affect edges here; throttling and
+    /// pruning are applied later by client-facing policies.
+    fn compute_edges(nodes: &[CincinnatiPayload]) -> Fallible<Vec<(u64, u64)>> {
+        let mut edges = vec![];
+        let mut last_barrier = 0u64;
+        for (index, release) in nodes.iter().enumerate() {
+            let target = index as u64;
+            for source in last_barrier..target {
+                edges.push((source, target));
+            }
+            if release.metadata.contains_key(metadata::BARRIER) {
+                last_barrier = target;
             }
-            start = target;
         }
 
         Ok(edges)
@@ -182,7 +266,209 @@ impl Graph {
                         .metadata
                         .insert(metadata::DURATION.to_string(), minutes.to_string());
                 }
+                if let Some(salt) = &rollout.salt {
+                    release
+                        .metadata
+                        .insert(metadata::ROLLOUT_SALT.to_string(), salt.clone());
+                }
+                if let Some(curve) = &rollout.curve {
+                    release
+                        .metadata
+                        .insert(metadata::ROLLOUT_CURVE.to_string(), curve.clone());
+                }
+                if let Some(steps) = &rollout.steps {
+                    release.metadata.insert(
+                        metadata::ROLLOUT_STEPS.to_string(),
+                        metadata::format_rollout_steps(steps),
+                    );
+                }
+                match rollout.state.as_ref().map(String::as_str) {
+                    Some(metadata::STATE_PAUSED) => {
+                        let epoch = rollout
+                            .paused_epoch
+                            .map(|e| e.to_string())
+                            .unwrap_or_default();
+                        release
+                            .metadata
+                            .insert(metadata::ROLLOUT_PAUSED.to_string(), epoch);
+                    }
+                    Some(metadata::STATE_ABORTED) => {
+                        release
+                            .metadata
+                            .insert(metadata::ROLLOUT_ABORTED.to_string(), true.to_string());
+                    }
+                    _ => {}
+                }
             }
         }
     }
 }
+
+/// Build a graph from a list of releases (oldest first), each described
+/// by a comma-separated list of update markers.
+#[cfg(test)]
+pub(crate) fn graph_with_markers(markers: &[&str]) -> Graph {
+    let mut releases = vec![];
+    let mut updates = vec![];
+    for (index, entry) in markers.iter().enumerate() {
+        let version = format!("30.{}", index);
+        releases.push(serde_json::json!({
+            "version": version,
+            "metadata": "",
+            "commits": [{ "architecture": "x86_64", "checksum": format!("sha{}", index) }],
+        }));
+
+        let mut meta = serde_json::Map::new();
+        for marker in entry.split(',').filter(|m| !m.is_empty()) {
+            let value = match marker {
+                "barrier" | "deadend" => serde_json::json!({ "reason": "" }),
+                "rollout" => serde_json::json!({ "start_percentage": 0.0 }),
+                _ => panic!("unknown marker '{}'", marker),
+            };
+            meta.insert(marker.to_string(), value);
+        }
+        updates.push(serde_json::json!({ "version": version, "metadata": meta }));
+    }
+
+    let releases = serde_json::from_value(serde_json::Value::Array(releases)).unwrap();
+    let updates = serde_json::from_value(serde_json::json!({
+        "stream": "testing",
+        "releases": updates,
+    }))
+    .unwrap();
+    Graph::from_metadata(releases, updates).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{self, Explanation};
+
+    /// Time at which policies are evaluated.
+    const NOW: i64 = 1_570_000_000;
+
+    fn sorted(mut edges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+        edges.sort();
+        edges
+    }
+
+    /// Expected edges for releases with the given markers.
+    struct EdgesCase {
+        desc: &'static str,
+        markers: &'static [&'static str],
+        edges: &'static [(u64, u64)],
+    }
+
+    #[test]
+    fn compute_edges() {
+        let cases = [
+            EdgesCase {
+                desc: "empty graph",
+                markers: &[],
+                edges: &[],
+            },
+            EdgesCase {
+                desc: "single release",
+                markers: &[""],
+                edges: &[],
+            },
+            EdgesCase {
+                desc: "plain releases are fully connected",
+                markers: &["", "", ""],
+                edges: &[(0, 1), (0, 2), (1, 2)],
+            },
+            EdgesCase {
+                desc: "barrier is a mandatory hop",
+                markers: &["", "barrier", ""],
+                edges: &[(0, 1), (1, 2)],
+            },
+            EdgesCase {
+                desc: "barrier as oldest release",
+                markers: &["barrier", "", ""],
+                edges: &[(0, 1), (0, 2), (1, 2)],
+            },
+            EdgesCase {
+                desc: "barrier as newest release",
+                markers: &["", "", "barrier"],
+                edges: &[(0, 1), (0, 2), (1, 2)],
+            },
+            EdgesCase {
+                desc: "consecutive barriers",
+                markers: &["", "barrier", "barrier", ""],
+                edges: &[(0, 1), (1, 2), (2, 3)],
+            },
+            EdgesCase {
+                desc: "releases between barriers",
+                markers: &["", "", "barrier", "", "", "barrier", ""],
+                edges: &[
+                    (0, 1),
+                    (0, 2),
+                    (1, 2),
+                    (2, 3),
+                    (2, 4),
+                    (2, 5),
+                    (3, 4),
+                    (3, 5),
+                    (4, 5),
+                    (5, 6),
+                ],
+            },
+            EdgesCase {
+                desc: "rollout does not affect edges",
+                markers: &["", "", "rollout"],
+                edges: &[(0, 1), (0, 2), (1, 2)],
+            },
+            EdgesCase {
+                desc: "rollout reachable back to the nearest barrier only",
+                markers: &["", "barrier", "", "barrier", "", "rollout"],
+                edges: &[(0, 1), (1, 2), (1, 3), (2, 3), (3, 4), (3, 5), (4, 5)],
+            },
+            EdgesCase {
+                desc: "rollout before a later barrier",
+                markers: &["", "rollout", "barrier", ""],
+                edges: &[(0, 1), (0, 2), (1, 2), (2, 3)],
+            },
+            EdgesCase {
+                desc: "rolled out barrier",
+                markers: &["", "barrier,rollout", ""],
+                edges: &[(0, 1), (1, 2)],
+            },
+            EdgesCase {
+                desc: "deadend does not affect edges",
+                markers: &["", "deadend", ""],
+                edges: &[(0, 1), (0, 2), (1, 2)],
+            },
+            EdgesCase {
+                desc: "deadend behind a barrier",
+                markers: &["deadend", "barrier", "deadend", "rollout"],
+                edges: &[(0, 1), (1, 2), (1, 3), (2, 3)],
+            },
+        ];
+
+        for case in &cases {
+            let graph = graph_with_markers(case.markers);
+            assert_eq!(graph.nodes.len(), case.markers.len(), "{}", case.desc);
+            assert_eq!(
+                sorted(graph.edges),
+                sorted(case.edges.to_vec()),
+                "{}",
+                case.desc
+            );
+        }
+    }
+
+    #[test]
+    fn update_path() {
+        let graph = graph_with_markers(&["", "", "barrier", "", "barrier", "", ""]);
+        assert_eq!(graph.update_path(0), vec![0, 2, 4, 6]);
+        assert_eq!(graph.update_path(3), vec![3, 4, 6]);
+        assert_eq!(graph.update_path(6), vec![6]);
+        assert_eq!(graph.update_path(7), Vec::<usize>::new());
+
+        let graph = graph_with_markers(&["", "barrier", "", "deadend", "", "rollout"]);
+        let graph = policy::filter_deadends(graph, &mut Explanation::disabled());
+        assert_eq!(graph.update_path(3), vec![3]);
+        let graph = policy::throttle_rollouts(graph, NOW, |_| 1.0, &mut Explanation::disabled());
+        assert_eq!(graph.update_path(0), vec![0, 1, 4]);
+    }
+}
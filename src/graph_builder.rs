@@ -0,0 +1,238 @@
+//! Graph-builder service.
+
+use crate::clock::{Clock, SystemClock};
+use crate::{config, export, health, metrics, negotiate, policy, scraper};
+use actix::prelude::*;
+use actix_web::{http::Method, middleware::Logger, server, App};
+use actix_web::{HttpRequest, HttpResponse};
+use failure::{Error, Fallible};
+use futures::future;
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Response header reporting where the served graph comes from.
+static GRAPH_SOURCE_HEADER: &str = "X-Dumnati-Graph-Source";
+
+/// Run the graph-builder service, scraping all configured streams.
+pub(crate) fn run(settings: config::Settings) -> Fallible<()> {
+    let sys = actix::System::new("dumnati-graph-builder");
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::default());
+    let mut scrapers = HashMap::with_capacity(settings.upstream.streams.len());
+    for stream in &settings.upstream.streams {
+        let addr = scraper::Scraper::new(stream, &settings.upstream, Arc::clone(&clock))?.start();
+        scrapers.insert(stream.clone(), addr);
+    }
+
+    let service_state = AppState {
+        scrapers: Arc::new(scrapers),
+        max_graph_age: settings.graph_builder.max_graph_age,
+        policies: Arc::new(settings.graph_builder.policies.clone()),
+        clock,
+    };
+    log::info!("policy chain: {}", service_state.policies);
+    let gb_service = service_state.clone();
+    let gb_status = service_state.clone();
+    let cfg = &settings.graph_builder.service;
+
+    // Graph-builder service.
+    server::new(move || {
+        App::with_state(gb_service.clone())
+            .middleware(Logger::default())
+            .route("/v1/graph", Method::GET, gb_serve_graph)
+    })
+    .bind((cfg.address, cfg.port))?
+    .start();
+
+    // Graph-builder status service.
+    server::new(move || {
+        App::with_state(gb_status.clone())
+            .middleware(Logger::default())
+            .route("/metrics", Method::GET, metrics::serve_metrics)
+            .route("/health/live", Method::GET, health::serve_live)
+            .route("/health/ready", Method::GET, gb_serve_ready)
+            .route("/debug/graph", Method::GET, gb_serve_raw_graph)
+            .route("/debug/explain", Method::GET, gb_serve_explain)
+            .route("/debug/graph_diffs", Method::GET, gb_serve_graph_diffs)
+    })
+    .bind((cfg.address, cfg.status_port))?
+    .start();
+
+    sys.run();
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AppState {
+    scrapers: Arc<HashMap<String, Addr<scraper::Scraper>>>,
+    max_graph_age: Option<std::time::Duration>,
+    policies: Arc<policy::PolicyChain>,
+    clock: Arc<dyn Clock>,
+}
+
+pub(crate) fn gb_serve_graph(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let format = match negotiate::GraphFormat::negotiate(&req) {
+        Ok(format) => format,
+        Err(resp) => return Box::new(future::ok(resp)),
+    };
+    let ctx = policy::RequestContext::new(req.query().clone(), None, req.state().clock.now());
+    if let Err(e) = policy::check_basearch(&ctx.basearch) {
+        return Box::new(future::ok(crate::invalid_param("basearch", &e)));
+    }
+    let policies = Arc::clone(&req.state().policies);
+
+    let scraper_addr = match req.state().scrapers.get(&ctx.stream) {
+        Some(addr) => addr,
+        None => return Box::new(future::ok(crate::unknown_stream(&ctx.stream))),
+    };
+    let cached_graph = scraper_addr
+        .send(scraper::GetCachedGraph {
+            stream: ctx.stream.clone(),
+        })
+        .flatten();
+
+    let resp = cached_graph.and_then(move |cached| {
+        let mut explain = policy::Explanation::disabled();
+        let graph = policies.apply(cached.graph, &ctx, &mut explain)?;
+        let mut resp = HttpResponse::Ok();
+        resp.header(GRAPH_SOURCE_HEADER, cached.status.source.as_str());
+        if let Some(age) = cached.status.age() {
+            resp.header(health::GRAPH_AGE_HEADER, age.as_secs().to_string());
+        }
+        format.respond(resp, &graph)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve the cached graph for a stream, before any policy is applied.
+///
+/// The graph can be rendered for humans via the `format` parameter.
+pub(crate) fn gb_serve_raw_graph(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let stream = req
+        .query()
+        .get("stream")
+        .map(String::from)
+        .unwrap_or_default();
+
+    let scraper_addr = match req.state().scrapers.get(&stream) {
+        Some(addr) => addr,
+        None => return Box::new(future::ok(crate::unknown_stream(&stream))),
+    };
+    let format = match req.query().get("format") {
+        Some(input) => match input.parse::<export::Format>() {
+            Ok(format) => format,
+            Err(e) => return Box::new(future::ok(crate::invalid_param("format", &e.to_string()))),
+        },
+        None => export::Format::Json,
+    };
+    let cached_graph = scraper_addr
+        .send(scraper::GetCachedGraph {
+            stream: stream.clone(),
+        })
+        .flatten();
+
+    let resp = cached_graph.and_then(move |cached| {
+        let body = format.render(&cached.graph, &stream)?;
+        let resp = HttpResponse::Ok()
+            .content_type(format.content_type())
+            .header(GRAPH_SOURCE_HEADER, cached.status.source.as_str())
+            .body(body);
+        Ok(resp)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve the graph for a client, along with the decisions of the graph-builder
+/// policies, for the policy-engine to explain.
+pub(crate) fn gb_serve_explain(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let ctx = policy::RequestContext::new(req.query().clone(), None, req.state().clock.now());
+    if let Err(e) = policy::check_basearch(&ctx.basearch) {
+        return Box::new(future::ok(crate::invalid_param("basearch", &e)));
+    }
+    let policies = Arc::clone(&req.state().policies);
+
+    let scraper_addr = match req.state().scrapers.get(&ctx.stream) {
+        Some(addr) => addr,
+        None => return Box::new(future::ok(crate::unknown_stream(&ctx.stream))),
+    };
+    let cached_graph = scraper_addr
+        .send(scraper::GetCachedGraph {
+            stream: ctx.stream.clone(),
+        })
+        .flatten();
+
+    let resp = cached_graph.and_then(move |cached| {
+        let mut explanation = policy::Explanation::recording();
+        let graph = policies.apply(cached.graph, &ctx, &mut explanation)?;
+        let body = policy::ExplainedGraph { explanation, graph };
+        let json = serde_json::to_string(&body)?;
+        let resp = HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json);
+        Ok(resp)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve the most recent changes to the cached graph for a stream, oldest first.
+pub(crate) fn gb_serve_graph_diffs(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let stream = req
+        .query()
+        .get("stream")
+        .map(String::from)
+        .unwrap_or_default();
+
+    let scraper_addr = match req.state().scrapers.get(&stream) {
+        Some(addr) => addr,
+        None => return Box::new(future::ok(crate::unknown_stream(&stream))),
+    };
+    let diffs = scraper_addr
+        .send(scraper::GetGraphDiffs {
+            stream: stream.clone(),
+        })
+        .flatten();
+
+    let resp = diffs.and_then(move |diffs| {
+        let body = serde_json::json!({
+            "stream": stream,
+            "diffs": diffs,
+        });
+        let json = serde_json::to_string_pretty(&body)?;
+        let resp = HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json);
+        Ok(resp)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve readiness probes, see `scraper::readiness_problems`.
+pub(crate) fn gb_serve_ready(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let max_age = req.state().max_graph_age;
+    let statuses: Vec<_> = req
+        .state()
+        .scrapers
+        .values()
+        .map(|addr| addr.send(scraper::GetGraphStatus {}).from_err())
+        .collect();
+
+    let resp = future::join_all(statuses)
+        .map(move |statuses| health::readiness(scraper::readiness_problems(&statuses, max_age)));
+
+    Box::new(resp)
+}
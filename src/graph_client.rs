@@ -0,0 +1,372 @@
+//! Graph-builder client, with caching.
+
+use crate::{config, graph, health, policy};
+use actix::prelude::*;
+use failure::{Error, Fallible};
+use futures::future;
+use futures::prelude::*;
+use prometheus::IntCounterVec;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref GB_FETCHES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_pe_graph_builder_fetches_total",
+        "Total number of graphs fetched from the graph-builder",
+        &["stream"]
+    )
+    .unwrap();
+    static ref CACHE_HITS: IntCounterVec = register_int_counter_vec!(
+        "dumnati_pe_graph_cache_hits_total",
+        "Total number of graph requests served from cache",
+        &["stream"]
+    )
+    .unwrap();
+}
+
+/// Graph, as served by the graph-builder.
+#[derive(Clone, Debug)]
+pub(crate) struct RemoteGraph {
+    pub(crate) graph: graph::Graph,
+    fetched: Instant,
+    upstream_age: Option<Duration>,
+}
+
+impl RemoteGraph {
+    /// Return the age of this graph, as reported by the graph-builder and
+    /// adjusted for the time spent in cache.
+    pub(crate) fn age(&self) -> Option<Duration> {
+        self.upstream_age.map(|age| age + self.fetched.elapsed())
+    }
+}
+
+/// Client for the graph-builder `/v1/graph` endpoint.
+#[derive(Clone, Debug)]
+pub struct GraphClient {
+    cache: HashMap<(String, String), RemoteGraph>,
+    cache_ttl: Duration,
+    streams: HashSet<String>,
+    graph_url: reqwest::Url,
+    explain_url: reqwest::Url,
+    ready_url: reqwest::Url,
+    hclient: reqwest::r#async::Client,
+}
+
+impl GraphClient {
+    /// Build a client for the graph-builder, serving the given `streams`.
+    pub(crate) fn new(cfg: &config::PolicyEngineSettings, streams: &[String]) -> Fallible<Self> {
+        let client = Self {
+            cache: HashMap::new(),
+            cache_ttl: cfg.graph_cache_ttl,
+            streams: streams.iter().cloned().collect(),
+            graph_url: cfg.graph_builder_url.join("v1/graph")?,
+            explain_url: cfg.graph_builder_status_url.join("debug/explain")?,
+            ready_url: cfg.graph_builder_status_url.join("health/ready")?,
+            hclient: reqwest::r#async::ClientBuilder::new().build()?,
+        };
+        Ok(client)
+    }
+
+    /// Fetch a graph from the graph-builder, `None` if the stream is unknown.
+    fn fetch_graph(
+        &self,
+        stream: &str,
+        basearch: &str,
+    ) -> impl Future<Item = Option<RemoteGraph>, Error = Error> {
+        let mut url = self.graph_url.clone();
+        url.query_pairs_mut()
+            .append_pair("stream", stream)
+            .append_pair("basearch", basearch);
+        self.fetch(url)
+    }
+
+    /// Fetch a graph explained by the graph-builder status service for a
+    /// client with the given `params`, `None` if the stream is unknown.
+    fn fetch_explained_graph(
+        &self,
+        params: &HashMap<String, String>,
+    ) -> impl Future<Item = Option<policy::ExplainedGraph>, Error = Error> {
+        let mut url = self.explain_url.clone();
+        url.query_pairs_mut().extend_pairs(params);
+        self.hclient.get(url).send().from_err().and_then(|resp| {
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return future::Either::A(future::ok(None));
+            }
+            let explained = future::result(resp.error_for_status())
+                .and_then(|mut resp| resp.json::<policy::ExplainedGraph>())
+                .from_err()
+                .map(Some);
+            future::Either::B(explained)
+        })
+    }
+
+    fn fetch(&self, url: reqwest::Url) -> impl Future<Item = Option<RemoteGraph>, Error = Error> {
+        self.hclient.get(url).send().from_err().and_then(|resp| {
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return future::Either::A(future::ok(None));
+            }
+            let upstream_age = resp
+                .headers()
+                .get(health::GRAPH_AGE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            let graph = future::result(resp.error_for_status())
+                .and_then(|mut resp| resp.json::<graph::Graph>())
+                .from_err()
+                .map(move |graph| {
+                    Some(RemoteGraph {
+                        graph,
+                        fetched: Instant::now(),
+                        upstream_age,
+                    })
+                });
+            future::Either::B(graph)
+        })
+    }
+
+    /// Return the cached graph for `key`, unless expired.
+    fn cached(&self, key: &(String, String)) -> Option<&RemoteGraph> {
+        self.cache
+            .get(key)
+            .filter(|cached| cached.fetched.elapsed() < self.cache_ttl)
+    }
+
+    /// Check graph-builder readiness, returning any reported problems.
+    ///
+    /// Graphs are only served fresh if the graph-builder has fresh graphs for
+    /// all streams, so its problems (e.g. stale streams) are also ours.
+    fn check_ready(&self) -> impl Future<Item = Vec<String>, Error = Error> {
+        self.hclient
+            .get(self.ready_url.clone())
+            .send()
+            .and_then(|resp| {
+                let status = resp.status();
+                resp.into_body().concat2().map(move |body| (status, body))
+            })
+            .then(|res| {
+                let problems = match res {
+                    Ok((status, _)) if status.is_success() => vec![],
+                    Ok((status, body)) => {
+                        let mut problems = vec![format!("graph-builder not ready ({})", status)];
+                        let details = String::from_utf8_lossy(&body);
+                        problems.extend(details.lines().map(String::from));
+                        problems
+                    }
+                    Err(e) => vec![format!("graph-builder unreachable: {}", e)],
+                };
+                Ok(problems)
+            })
+    }
+}
+
+impl Actor for GraphClient {
+    type Context = Context<Self>;
+}
+
+pub(crate) struct GetGraph {
+    pub(crate) stream: String,
+    pub(crate) basearch: String,
+}
+
+impl Message for GetGraph {
+    type Result = Result<Option<RemoteGraph>, Error>;
+}
+
+impl Handler<GetGraph> for GraphClient {
+    type Result = ResponseActFuture<Self, Option<RemoteGraph>, Error>;
+
+    fn handle(&mut self, msg: GetGraph, _ctx: &mut Self::Context) -> Self::Result {
+        // Unknown streams are not fetched, nor cached.
+        if !self.streams.contains(&msg.stream) {
+            return Box::new(actix::fut::ok(None));
+        }
+        let key = (msg.stream, msg.basearch);
+        if let Some(cached) = self.cached(&key) {
+            CACHE_HITS.with_label_values(&[&key.0]).inc();
+            return Box::new(actix::fut::ok(Some(cached.clone())));
+        }
+
+        GB_FETCHES.with_label_values(&[&key.0]).inc();
+        let fetch = self.fetch_graph(&key.0, &key.1);
+        let update_cache =
+            actix::fut::wrap_future::<_, Self>(fetch).map(move |graph, actor, _ctx| {
+                match &graph {
+                    Some(remote) => {
+                        actor.cache.insert(key, remote.clone());
+                    }
+                    None => {
+                        actor.cache.remove(&key);
+                    }
+                };
+                graph
+            });
+
+        Box::new(update_cache)
+    }
+}
+
+/// Fetch a graph explained by the graph-builder, bypassing the cache.
+pub(crate) struct GetExplainedGraph {
+    pub(crate) stream: String,
+    /// Client parameters, forwarded to the graph-builder policies.
+    pub(crate) params: HashMap<String, String>,
+}
+
+impl Message for GetExplainedGraph {
+    type Result = Result<Option<policy::ExplainedGraph>, Error>;
+}
+
+impl Handler<GetExplainedGraph> for GraphClient {
+    type Result = ResponseFuture<Option<policy::ExplainedGraph>, Error>;
+
+    fn handle(&mut self, msg: GetExplainedGraph, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.streams.contains(&msg.stream) {
+            return Box::new(future::ok(None));
+        }
+        Box::new(self.fetch_explained_graph(&msg.params))
+    }
+}
+
+pub(crate) struct CheckReady {}
+
+impl Message for CheckReady {
+    type Result = Result<Vec<String>, Error>;
+}
+
+impl Handler<CheckReady> for GraphClient {
+    type Result = ResponseFuture<Vec<String>, Error>;
+
+    fn handle(&mut self, _msg: CheckReady, _ctx: &mut Self::Context) -> Self::Result {
+        Box::new(self.check_ready())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Graph-builder client for tests, never actually contacting it.
+    fn client(cache_ttl: Duration) -> GraphClient {
+        let base = reqwest::Url::parse("http://127.0.0.1:9/").unwrap();
+        GraphClient {
+            cache: HashMap::new(),
+            cache_ttl,
+            streams: vec!["testing".to_string()].into_iter().collect(),
+            graph_url: base.join("v1/graph").unwrap(),
+            explain_url: base.join("debug/explain").unwrap(),
+            ready_url: base.join("health/ready").unwrap(),
+            hclient: reqwest::r#async::ClientBuilder::new().build().unwrap(),
+        }
+    }
+
+    /// Graph fetched `secs` seconds ago, reported as `upstream_age` old back then.
+    fn remote(secs: u64, upstream_age: Option<Duration>) -> RemoteGraph {
+        RemoteGraph {
+            graph: graph::Graph::default(),
+            fetched: Instant::now() - Duration::from_secs(secs),
+            upstream_age,
+        }
+    }
+
+    #[test]
+    fn cache_ttl() {
+        let key = |stream: &str| (stream.to_string(), "x86_64".to_string());
+        let mut client = client(Duration::from_secs(30));
+        assert!(client.cached(&key("stable")).is_none());
+
+        client.cache.insert(key("stable"), remote(60, None));
+        assert!(client.cached(&key("stable")).is_none());
+
+        client.cache.insert(key("testing"), remote(0, None));
+        assert!(client.cached(&key("testing")).is_some());
+        assert!(client
+            .cached(&(key("testing").0, "aarch64".to_string()))
+            .is_none());
+
+        client.cache_ttl = Duration::from_secs(0);
+        assert!(client.cached(&key("testing")).is_none());
+    }
+
+    /// Graph-builder client, whose readiness probe gets `response` back.
+    fn probed_client(response: &'static str) -> GraphClient {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = conn.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            conn.write_all(response.as_bytes()).unwrap();
+        });
+        let mut client = client(Duration::from_secs(30));
+        client.ready_url = reqwest::Url::parse(&format!("http://{}/health/ready", addr)).unwrap();
+        client
+    }
+
+    #[test]
+    fn readiness_probe() {
+        let mut sys = actix::System::new("dumnati-test");
+
+        let ready = probed_client("HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n");
+        assert!(sys.block_on(ready.check_ready()).unwrap().is_empty());
+
+        let stale = probed_client(
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 44\r\n\r\n\
+             stream 'testing': graph is stale (900s old)\n",
+        );
+        assert_eq!(
+            sys.block_on(stale.check_ready()).unwrap(),
+            vec![
+                "graph-builder not ready (503 Service Unavailable)",
+                "stream 'testing': graph is stale (900s old)",
+            ]
+        );
+
+        // Cached graphs do not make up for an unreachable graph-builder.
+        let mut unreachable = client(Duration::from_secs(30));
+        let key = ("testing".to_string(), "x86_64".to_string());
+        unreachable.cache.insert(key, remote(0, None));
+        let problems = sys.block_on(unreachable.check_ready()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("graph-builder unreachable"));
+    }
+
+    #[test]
+    fn unknown_streams() {
+        let mut sys = actix::System::new("dumnati-test");
+        let client = client(Duration::from_secs(30)).start();
+
+        let graph = |stream: &str| GetGraph {
+            stream: stream.to_string(),
+            basearch: "x86_64".to_string(),
+        };
+        let explained_graph = |stream: &str| GetExplainedGraph {
+            stream: stream.to_string(),
+            params: HashMap::new(),
+        };
+        // The graph-builder is unreachable, so only unknown streams succeed.
+        assert!(sys
+            .block_on(client.send(graph("unknown")).flatten())
+            .unwrap()
+            .is_none());
+        assert!(sys
+            .block_on(client.send(explained_graph("unknown")).flatten())
+            .unwrap()
+            .is_none());
+        sys.block_on(client.send(graph("testing")).flatten())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn graph_age() {
+        assert_eq!(remote(10, None).age(), None);
+        let age = remote(10, Some(Duration::from_secs(5))).age().unwrap();
+        assert!(age >= Duration::from_secs(15) && age < Duration::from_secs(20));
+    }
+}
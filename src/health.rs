@@ -0,0 +1,53 @@
+//! Health endpoints.
+
+use actix_web::{HttpRequest, HttpResponse};
+
+/// Response header reporting the age of the served graph, in seconds.
+pub(crate) static GRAPH_AGE_HEADER: &str = "X-Graph-Age";
+
+/// Serve liveness probes.
+pub(crate) fn serve_live<S>(_req: HttpRequest<S>) -> HttpResponse {
+    HttpResponse::Ok().body("ok\n")
+}
+
+/// Build a readiness response, failing if there are any `problems`.
+pub(crate) fn readiness(problems: Vec<String>) -> HttpResponse {
+    if problems.is_empty() {
+        return HttpResponse::Ok().body("ok\n");
+    }
+
+    let mut body = problems.join("\n");
+    body.push('\n');
+    HttpResponse::ServiceUnavailable().body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, Body};
+
+    fn body(resp: &HttpResponse) -> String {
+        match resp.body() {
+            Body::Binary(bin) => String::from_utf8_lossy(bin.as_ref()).into_owned(),
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn readiness_response() {
+        let resp = readiness(vec![]);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(&resp), "ok\n");
+
+        let problems = vec![
+            "stream 'stable': no graph yet".to_string(),
+            "stream 'testing': graph is stale".to_string(),
+        ];
+        let resp = readiness(problems);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body(&resp),
+            "stream 'stable': no graph yet\nstream 'testing': graph is stale\n"
+        );
+    }
+}
@@ -14,234 +14,101 @@ extern crate structopt;
 #[macro_use]
 extern crate prometheus;
 
+mod clock;
+mod config;
+mod diff;
+mod export;
 mod graph;
+mod graph_builder;
+mod graph_client;
+mod health;
 mod metadata;
 mod metrics;
+mod negotiate;
+mod overrides;
 mod policy;
+mod policy_engine;
 mod scraper;
+mod simulate;
+mod snapshot;
+mod upstream;
+mod validate;
 
-use actix::prelude::*;
-use actix_web::{http::Method, middleware::Logger, server, App};
-use actix_web::{HttpRequest, HttpResponse};
-use failure::{Error, Fallible};
-use futures::prelude::*;
-use prometheus::{Histogram, IntCounter};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::sync::Arc;
+use actix_web::HttpResponse;
+use failure::Fallible;
 use structopt::StructOpt;
 
-lazy_static::lazy_static! {
-    static ref V1_GRAPH_INCOMING_REQS: IntCounter = register_int_counter!(opts!(
-        "dumnati_pe_v1_graph_incoming_requests_total",
-        "Total number of incoming HTTP client request to /v1/graph"
-    ))
-    .unwrap();
-    static ref UNIQUE_IDS: IntCounter = register_int_counter!(opts!(
-        "dumnati_pe_v1_graph_unique_uuids_total",
-        "Total number of unique node UUIDs (per-instance Bloom filter)."
-    ))
-    .unwrap();
-    static ref ROLLOUT_WARINESS: Histogram = register_histogram!(
-        "dumnati_pe_v1_graph_rollout_wariness",
-        "Per-request rollout wariness.",
-        prometheus::linear_buckets(0.0, 0.1, 11).unwrap()
-    )
-    .unwrap();
-}
-
 fn main() -> Fallible<()> {
     env_logger::Builder::from_default_env().try_init()?;
 
     let opts = CliOptions::from_args();
     trace!("started with CLI options: {:#?}", opts);
 
-    let sys = actix::System::new("dumnati");
-
-    let scraper_addr = scraper::Scraper::new("testing")?.start();
-
-    let node_population = Arc::new(cbloom::Filter::new(10 * 1024 * 1024, 1_000_000));
-    let service_state = AppState {
-        scraper_addr,
-        population: Arc::clone(&node_population),
-    };
-    let gb_service = service_state.clone();
-    let gb_status = service_state.clone();
-    let pe_service = service_state.clone();
-    let pe_status = service_state.clone();
-
-    // Graph-builder service.
-    server::new(move || {
-        App::with_state(gb_service.clone())
-            .middleware(Logger::default())
-            .route("/v1/graph", Method::GET, gb_serve_graph)
-    })
-    .bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), 8080))?
-    .start();
-
-    // Graph-builder status service.
-    server::new(move || {
-        App::with_state(gb_status.clone())
-            .middleware(Logger::default())
-            .route("/metrics", Method::GET, metrics::serve_metrics)
-    })
-    .bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), 9080))?
-    .start();
-
-    // Policy-engine service.
-    server::new(move || {
-        App::with_state(pe_service.clone())
-            .middleware(Logger::default())
-            .route("/v1/graph", Method::GET, pe_serve_graph)
-    })
-    .bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), 8081))?
-    .start();
-
-    // Policy-engine status service.
-    server::new(move || {
-        App::with_state(pe_status.clone())
-            .middleware(Logger::default())
-            .route("/metrics", Method::GET, metrics::serve_metrics)
-    })
-    .bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), 9081))?
-    .start();
-
-    sys.run();
-    Ok(())
-}
+    let settings = config::Settings::assemble(opts.config_path.as_ref().map(String::as_str))?;
+    trace!("runtime settings: {:#?}", settings);
 
-#[derive(Clone, Debug)]
-pub(crate) struct AppState {
-    scraper_addr: Addr<scraper::Scraper>,
-    population: Arc<cbloom::Filter>,
+    match opts.cmd {
+        CliCommand::GraphBuilder => graph_builder::run(settings),
+        CliCommand::PolicyEngine => policy_engine::run(settings),
+        CliCommand::Simulate(sim_opts) => simulate::run(settings, sim_opts),
+        CliCommand::Validate(sources) => validate::run(settings, sources),
+        CliCommand::Export(export_opts) => export::run(settings, export_opts),
+    }
 }
 
-pub(crate) fn gb_serve_graph(
-    req: HttpRequest<AppState>,
-) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-    let basearch = req
-        .query()
-        .get("basearch")
-        .map(String::from)
-        .unwrap_or_default();
-    let stream = req
-        .query()
-        .get("stream")
-        .map(String::from)
-        .unwrap_or_default();
-
-    let cached_graph = req
-        .state()
-        .scraper_addr
-        .send(scraper::GetCachedGraph { stream })
-        .flatten();
-
-    let resp = cached_graph
-        .and_then(|graph| policy::pick_basearch(graph, basearch))
-        .map(|graph| policy::filter_deadends(graph))
-        .and_then(|graph| {
-            serde_json::to_string_pretty(&graph).map_err(|e| failure::format_err!("{}", e))
-        })
-        .map(|json| {
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .body(json)
-        });
-
-    Box::new(resp)
+/// Reject a request targeting a stream which is not being served.
+pub(crate) fn unknown_stream(stream: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "kind": "unknown_stream",
+        "value": format!("unknown stream '{}'", stream),
+    });
+    HttpResponse::NotFound()
+        .content_type("application/json")
+        .body(body.to_string())
 }
 
-pub(crate) fn pe_serve_graph(
-    req: HttpRequest<AppState>,
-) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-    pe_record_metrics(&req);
-
-    let basearch = req
-        .query()
-        .get("basearch")
-        .map(String::from)
-        .unwrap_or_default();
-    let stream = req
-        .query()
-        .get("stream")
-        .map(String::from)
-        .unwrap_or_default();
-
-    let wariness = compute_wariness(&req.query());
-    ROLLOUT_WARINESS.observe(wariness);
-
-    let cached_graph = req
-        .state()
-        .scraper_addr
-        .send(scraper::GetCachedGraph { stream })
-        .flatten();
-
-    let resp = cached_graph
-        .and_then(|graph| policy::pick_basearch(graph, basearch))
-        .map(move |graph| policy::throttle_rollouts(graph, wariness))
-        .map(|graph| policy::filter_deadends(graph))
-        .and_then(|graph| {
-            serde_json::to_string_pretty(&graph).map_err(|e| failure::format_err!("{}", e))
-        })
-        .map(|json| {
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .body(json)
-        });
-
-    Box::new(resp)
+/// Reject a request from a client running a version not in the graph.
+pub(crate) fn unknown_version(version: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "kind": "unknown_version",
+        "value": format!("unknown version '{}'", version),
+    });
+    HttpResponse::NotFound()
+        .content_type("application/json")
+        .body(body.to_string())
 }
 
-fn compute_wariness(params: &HashMap<String, String>) -> f64 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-
-    if let Ok(input) = params
-        .get("rollout_wariness")
-        .map(String::from)
-        .unwrap_or_default()
-        .parse::<f64>()
-    {
-        let wariness = input.max(0.0).min(1.0);
-        return wariness;
-    }
+/// Reject a request which is not allowed.
+pub(crate) fn forbidden(reason: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "kind": "forbidden",
+        "value": reason,
+    });
+    HttpResponse::Forbidden()
+        .content_type("application/json")
+        .body(body.to_string())
+}
 
-    let uuid = params
-        .get("node_uuid")
-        .map(String::from)
-        .unwrap_or_default();
-    let wariness = {
-        // Left limit not included in range.
-        const COMPUTED_MIN: f64 = 0.0 + 0.000001;
-        const COMPUTED_MAX: f64 = 1.0;
-        let mut hasher = DefaultHasher::new();
-        uuid.hash(&mut hasher);
-        let digest = hasher.finish();
-        // Scale down.
-        let scaled = (digest as f64) / (std::u64::MAX as f64);
-        // Clamp within limits.
-        scaled.max(COMPUTED_MIN).min(COMPUTED_MAX)
-    };
-
-    wariness
+/// Reject a request asking for a representation which cannot be served.
+pub(crate) fn not_acceptable(reason: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "kind": "not_acceptable",
+        "value": reason,
+    });
+    HttpResponse::NotAcceptable()
+        .content_type("application/json")
+        .body(body.to_string())
 }
 
-pub(crate) fn pe_record_metrics(req: &HttpRequest<AppState>) {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-
-    V1_GRAPH_INCOMING_REQS.inc();
-
-    let population = &req.state().population;
-    if let Some(uuid) = req.query().get("node_uuid") {
-        let mut hasher = DefaultHasher::default();
-        uuid.hash(&mut hasher);
-        let client_uuid = hasher.finish();
-        if !population.maybe_contains(client_uuid) {
-            population.insert(client_uuid);
-            UNIQUE_IDS.inc();
-        }
-    }
+/// Reject a request carrying a malformed parameter.
+pub(crate) fn invalid_param(name: &str, reason: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "kind": "invalid_param",
+        "value": format!("invalid parameter '{}': {}", name, reason),
+    });
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .body(body.to_string())
 }
 
 #[derive(Debug, StructOpt)]
@@ -249,4 +116,53 @@ pub(crate) struct CliOptions {
     /// Path to configuration file.
     #[structopt(short = "c")]
     pub config_path: Option<String>,
+    #[structopt(subcommand)]
+    pub cmd: CliCommand,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum CliCommand {
+    /// Run the graph-builder service.
+    #[structopt(name = "graph-builder")]
+    GraphBuilder,
+    /// Run the policy-engine service.
+    #[structopt(name = "policy-engine")]
+    PolicyEngine,
+    /// Simulate rollouts over time, for a synthetic population of nodes.
+    #[structopt(name = "simulate")]
+    Simulate(simulate::SimulateOptions),
+    /// Check upstream metadata for inconsistencies.
+    #[structopt(name = "validate")]
+    Validate(upstream::SourceOptions),
+    /// Export the update graph as JSON, Graphviz DOT or Mermaid.
+    #[structopt(name = "export")]
+    Export(export::ExportOptions),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommand_parsing() {
+        let opts =
+            CliOptions::from_iter_safe(&["dumnati", "-c", "x.toml", "graph-builder"]).unwrap();
+        assert_eq!(
+            opts.config_path.as_ref().map(String::as_str),
+            Some("x.toml")
+        );
+        match opts.cmd {
+            CliCommand::GraphBuilder => {}
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        };
+        let opts = CliOptions::from_iter_safe(&["dumnati", "policy-engine"]).unwrap();
+        assert_eq!(opts.config_path, None);
+        match opts.cmd {
+            CliCommand::PolicyEngine => {}
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        };
+
+        assert!(CliOptions::from_iter_safe(&["dumnati", "-c", "x.toml"]).is_err());
+        assert!(CliOptions::from_iter_safe(&["dumnati", "-c", "x.toml", "scrape"]).is_err());
+    }
 }
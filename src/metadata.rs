@@ -9,6 +9,9 @@ pub static RELEASES_JSON: &str =
 /// Templated URL for stream metadata.
 pub static STREAM_JSON: &str = "https://builds.coreos.fedoraproject.org/updates/${stream}.json";
 
+/// Architectures for which payloads can be served.
+pub static BASEARCHES: &[&str] = &["aarch64", "ppc64le", "s390x", "x86_64"];
+
 pub static SCHEME: &str = "org.fedoraproject.coreos.scheme";
 
 pub static AGE_INDEX: &str = "org.fedoraproject.coreos.releases.age_index";
@@ -22,6 +25,23 @@ pub static ROLLOUT: &str = "org.fedoraproject.coreos.updates.rollout";
 pub static DURATION: &str = "org.fedoraproject.coreos.updates.duration_minutes";
 pub static START_EPOCH: &str = "org.fedoraproject.coreos.updates.start_epoch";
 pub static START_VALUE: &str = "org.fedoraproject.coreos.updates.start_value";
+pub static ROLLOUT_SALT: &str = "org.fedoraproject.coreos.updates.rollout_salt";
+pub static ROLLOUT_CURVE: &str = "org.fedoraproject.coreos.updates.rollout_curve";
+pub static ROLLOUT_STEPS: &str = "org.fedoraproject.coreos.updates.rollout_steps";
+pub static ROLLOUT_PAUSED: &str = "org.fedoraproject.coreos.updates.rollout_paused_epoch";
+pub static ROLLOUT_ABORTED: &str = "org.fedoraproject.coreos.updates.rollout_aborted";
+
+/// Rollout curves.
+pub const CURVE_LINEAR: &str = "linear";
+pub const CURVE_EXPONENTIAL: &str = "exponential";
+pub const CURVE_STEPPED: &str = "stepped";
+pub static ROLLOUT_CURVES: &[&str] = &[CURVE_LINEAR, CURVE_EXPONENTIAL, CURVE_STEPPED];
+
+/// Rollout states.
+pub const STATE_ACTIVE: &str = "active";
+pub const STATE_PAUSED: &str = "paused";
+pub const STATE_ABORTED: &str = "aborted";
+pub static ROLLOUT_STATES: &[&str] = &[STATE_ACTIVE, STATE_PAUSED, STATE_ABORTED];
 
 /// Fedora CoreOS release index.
 #[derive(Debug, Deserialize)]
@@ -33,7 +53,6 @@ pub struct ReleasesJSON {
 pub struct Release {
     pub commits: Vec<ReleaseCommit>,
     pub version: String,
-    pub metadata: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -45,7 +64,6 @@ pub struct ReleaseCommit {
 /// Fedora CoreOS updates metadata
 #[derive(Debug, Deserialize)]
 pub struct UpdatesJSON {
-    pub stream: String,
     pub releases: Vec<ReleaseUpdate>,
 }
 
@@ -77,4 +95,61 @@ pub struct UpdateRollout {
     pub start_epoch: Option<i64>,
     pub start_percentage: Option<f64>,
     pub duration_minutes: Option<u64>,
+    /// Salt mixed into client wariness for this rollout.
+    pub salt: Option<String>,
+    /// Rollout curve, linear by default.
+    pub curve: Option<String>,
+    /// Steps of a stepped rollout.
+    pub steps: Option<Vec<RolloutStep>>,
+    /// Rollout state, active by default.
+    pub state: Option<String>,
+    /// When a paused rollout was paused.
+    pub paused_epoch: Option<i64>,
+}
+
+/// Step of a stepped rollout, reached `offset_minutes` after its start.
+#[derive(Debug, Deserialize)]
+pub struct RolloutStep {
+    pub offset_minutes: u64,
+    pub percentage: f64,
+}
+
+/// Format rollout steps as graph metadata, i.e. comma-separated
+/// `offset_minutes=percentage` pairs ordered by offset.
+pub fn format_rollout_steps(steps: &[RolloutStep]) -> String {
+    let mut pairs: Vec<_> = steps
+        .iter()
+        .map(|step| (step.offset_minutes, step.percentage))
+        .collect();
+    pairs.sort_by_key(|(offset, _)| *offset);
+    let pairs: Vec<_> = pairs
+        .into_iter()
+        .map(|(offset, percentage)| format!("{}={}", offset, percentage))
+        .collect();
+    pairs.join(",")
+}
+
+/// Parse rollout steps from graph metadata, skipping malformed entries.
+pub fn parse_rollout_steps(input: &str) -> Vec<(u64, f64)> {
+    input
+        .split(',')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let offset = kv.next()?.trim().parse::<u64>().ok()?;
+            let percentage = kv.next()?.trim().parse::<f64>().ok()?;
+            Some((offset, percentage))
+        })
+        .collect()
+}
+
+/// Build releases without commits, one per version.
+#[cfg(test)]
+pub(crate) fn test_releases(versions: &[&str]) -> Vec<Release> {
+    versions
+        .iter()
+        .map(|v| Release {
+            version: v.to_string(),
+            commits: vec![],
+        })
+        .collect()
 }
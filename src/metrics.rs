@@ -1,14 +1,12 @@
 //! Metrics endpoint.
 
-use crate::AppState;
 use actix_web::{HttpRequest, HttpResponse};
 use futures::future;
 use futures::prelude::*;
-use prometheus;
 
 /// Serve metrics requests (Prometheus textual format).
-pub(crate) fn serve_metrics(
-    _req: HttpRequest<AppState>,
+pub(crate) fn serve_metrics<S>(
+    _req: HttpRequest<S>,
 ) -> Box<dyn Future<Item = HttpResponse, Error = failure::Error>> {
     use prometheus::Encoder;
 
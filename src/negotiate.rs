@@ -0,0 +1,195 @@
+//! Content negotiation for graph responses.
+//!
+//! Graphs are served as compact JSON by default, or pretty-printed on
+//! `?pretty=1`. Clients can ask for the versioned Cincinnati media type via
+//! `Accept`, and for zstd compression via `Accept-Encoding`; other encodings
+//! (gzip, deflate, brotli) are negotiated by actix-web itself.
+
+use crate::graph::Graph;
+use actix_web::http::{header, ContentEncoding};
+use actix_web::{dev::HttpResponseBuilder, HttpRequest, HttpResponse};
+use failure::Fallible;
+
+/// Versioned media type for Cincinnati graphs.
+pub(crate) static CINCINNATI_MEDIA_TYPE: &str = "application/vnd.redhat.cincinnati.v1+json";
+/// Generic JSON media type.
+static JSON_MEDIA_TYPE: &str = "application/json";
+/// Served media types, by preference when equally acceptable.
+static MEDIA_TYPES: &[&str] = &[JSON_MEDIA_TYPE, CINCINNATI_MEDIA_TYPE];
+/// Token for zstd content encoding.
+static ZSTD_ENCODING: &str = "zstd";
+/// Encodings negotiated by actix-web itself.
+static BUILTIN_ENCODINGS: &[&str] = &["gzip", "deflate", "br"];
+/// zstd compression level.
+static ZSTD_LEVEL: i32 = 3;
+
+/// Negotiated representation of a graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct GraphFormat {
+    pub(crate) media_type: &'static str,
+    pub(crate) pretty: bool,
+    pub(crate) zstd: bool,
+}
+
+impl GraphFormat {
+    /// Negotiate the representation of a graph, from request headers and parameters.
+    pub(crate) fn negotiate<S>(req: &HttpRequest<S>) -> Result<Self, HttpResponse> {
+        let header_value = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let pretty = match req.query().get("pretty").map(String::as_str) {
+            None | Some("") | Some("0") | Some("false") => false,
+            Some("1") | Some("true") => true,
+            Some(_) => {
+                return Err(crate::invalid_param(
+                    "pretty",
+                    "expected '1', 'true', '0' or 'false'",
+                ))
+            }
+        };
+        let media_type = match pick_media_type(&header_value(header::ACCEPT)) {
+            Some(media_type) => media_type,
+            None => {
+                let reason = format!("supported media types: {}", MEDIA_TYPES.join(", "));
+                return Err(crate::not_acceptable(&reason));
+            }
+        };
+        let zstd = accepts_encoding(&header_value(header::ACCEPT_ENCODING), ZSTD_ENCODING);
+
+        Ok(Self {
+            media_type,
+            pretty,
+            zstd,
+        })
+    }
+
+    /// Finish building a response serving `graph`.
+    pub(crate) fn respond(
+        self,
+        mut resp: HttpResponseBuilder,
+        graph: &Graph,
+    ) -> Fallible<HttpResponse> {
+        let json = if self.pretty {
+            serde_json::to_vec_pretty(graph)?
+        } else {
+            serde_json::to_vec(graph)?
+        };
+        resp.content_type(self.media_type)
+            .header(header::VARY, "Accept, Accept-Encoding");
+        if !self.zstd {
+            return Ok(resp.body(json));
+        }
+
+        let compressed = zstd::encode_all(json.as_slice(), ZSTD_LEVEL)?;
+        resp.content_encoding(ContentEncoding::Identity)
+            .header(header::CONTENT_ENCODING, ZSTD_ENCODING);
+        Ok(resp.body(compressed))
+    }
+}
+
+/// Parse a list header (e.g. `Accept`) into values and their quality.
+fn parse_quality_list(input: &str) -> Vec<(String, f32)> {
+    input
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let value = parts.next().filter(|v| !v.is_empty())?.to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| {
+                    let mut kv = param.splitn(2, '=').map(str::trim);
+                    match (kv.next(), kv.next()) {
+                        (Some("q"), Some(q)) => q.parse::<f32>().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((value, quality))
+        })
+        .collect()
+}
+
+/// Pick the most acceptable media type, if any, given an `Accept` header.
+///
+/// A missing or empty header accepts anything.
+fn pick_media_type(accept: &str) -> Option<&'static str> {
+    let ranges = parse_quality_list(accept);
+    if ranges.is_empty() {
+        return Some(JSON_MEDIA_TYPE);
+    }
+
+    let mut best: Option<(&'static str, f32)> = None;
+    for media_type in MEDIA_TYPES {
+        let kind = format!("{}/*", media_type.split('/').next().unwrap_or_default());
+        // The most specific matching range determines quality.
+        let quality = [*media_type, kind.as_str(), "*/*"]
+            .iter()
+            .filter_map(|candidate| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range == candidate)
+                    .map(|(_, q)| *q)
+            })
+            .next()
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) {
+            best = Some((media_type, quality));
+        }
+    }
+    best.map(|(media_type, _)| media_type)
+}
+
+/// Check whether an `Accept-Encoding` header prefers `encoding`, which must be
+/// explicitly listed, over the encodings actix-web negotiates by itself.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    let codings = parse_quality_list(accept_encoding);
+    let quality = match codings.iter().find(|(value, _)| value == encoding) {
+        Some((_, q)) if *q > 0.0 => *q,
+        _ => return false,
+    };
+    codings
+        .iter()
+        .filter(|(value, _)| BUILTIN_ENCODINGS.contains(&value.as_str()) || value == "*")
+        .all(|(_, q)| quality > *q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(pick_media_type(""), Some(JSON_MEDIA_TYPE));
+        assert_eq!(pick_media_type("*/*"), Some(JSON_MEDIA_TYPE));
+        assert_eq!(pick_media_type("application/*"), Some(JSON_MEDIA_TYPE));
+        assert_eq!(
+            pick_media_type(CINCINNATI_MEDIA_TYPE),
+            Some(CINCINNATI_MEDIA_TYPE)
+        );
+        assert_eq!(
+            pick_media_type("application/json;q=0.5, application/vnd.redhat.cincinnati.v1+json"),
+            Some(CINCINNATI_MEDIA_TYPE)
+        );
+        assert_eq!(
+            pick_media_type("application/json;q=0, */*"),
+            Some(CINCINNATI_MEDIA_TYPE)
+        );
+        assert_eq!(pick_media_type("text/html"), None);
+        assert_eq!(pick_media_type("text/html, application/json; q=0"), None);
+
+        assert!(accepts_encoding("zstd", "zstd"));
+        assert!(accepts_encoding("gzip;q=0.5, zstd", "zstd"));
+        assert!(accepts_encoding("zstd, *;q=0.1", "zstd"));
+        assert!(!accepts_encoding("gzip, zstd", "zstd"));
+        assert!(!accepts_encoding("gzip;q=1.0, zstd;q=0.5", "zstd"));
+        assert!(!accepts_encoding("br;q=0.9, zstd;q=0.8", "zstd"));
+        assert!(!accepts_encoding("*, zstd", "zstd"));
+        assert!(!accepts_encoding("gzip, zstd;q=0", "zstd"));
+        assert!(!accepts_encoding("gzip, *", "zstd"));
+    }
+}
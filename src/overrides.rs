@@ -0,0 +1,87 @@
+//! Local rollout overrides.
+//!
+//! Operators can override the state of in-progress rollouts via a local TOML
+//! file, without waiting for upstream metadata to change. The file has one
+//! table per stream, mapping release versions to rollout states:
+//!
+//! ```toml
+//! [stable]
+//! "30.20191014.0" = "paused"
+//! ```
+
+use crate::metadata;
+use failure::{bail, format_err, Fallible};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Read the rollout overrides for `stream`, keyed by release version.
+///
+/// A missing file is the same as an empty one.
+pub(crate) fn read_rollout_overrides(
+    path: &Path,
+    stream: &str,
+) -> Fallible<HashMap<String, String>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => bail!("failed to read '{}': {}", path.display(), e),
+    };
+    parse_rollout_overrides(&content, stream)
+        .map_err(|e| format_err!("invalid rollout overrides '{}': {}", path.display(), e))
+}
+
+/// Parse the rollout overrides for `stream` from TOML `content`.
+fn parse_rollout_overrides(content: &str, stream: &str) -> Fallible<HashMap<String, String>> {
+    let mut all: HashMap<String, HashMap<String, String>> = toml::from_str(content)?;
+    let overrides = all.remove(stream).unwrap_or_default();
+    for (version, state) in &overrides {
+        if !metadata::ROLLOUT_STATES.contains(&state.as_str()) {
+            bail!("unknown state '{}' for release '{}'", state, version);
+        }
+    }
+    Ok(overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollout_overrides_parsing() {
+        let content = r#"
+            [testing]
+            "30.1" = "paused"
+            "30.2" = "aborted"
+            "30.3" = "active"
+
+            [stable]
+            "29.9" = "paused"
+        "#;
+        let overrides = parse_rollout_overrides(content, "testing").unwrap();
+        assert_eq!(
+            overrides,
+            hashmap! {
+                "30.1".to_string() => "paused".to_string(),
+                "30.2".to_string() => "aborted".to_string(),
+                "30.3".to_string() => "active".to_string(),
+            }
+        );
+        assert!(parse_rollout_overrides(content, "next").unwrap().is_empty());
+        assert!(parse_rollout_overrides("", "testing").unwrap().is_empty());
+
+        let unknown = "[testing]\n\"30.1\" = \"halted\"\n";
+        let err = parse_rollout_overrides(unknown, "testing").unwrap_err();
+        assert_eq!(err.to_string(), "unknown state 'halted' for release '30.1'");
+        // Other streams are validated only when read.
+        assert!(parse_rollout_overrides(unknown, "stable")
+            .unwrap()
+            .is_empty());
+        assert!(parse_rollout_overrides("testing = 1", "testing").is_err());
+    }
+
+    #[test]
+    fn missing_rollout_overrides() {
+        let path = Path::new("/nonexistent/rollout-overrides.toml");
+        assert!(read_rollout_overrides(path, "testing").unwrap().is_empty());
+    }
+}
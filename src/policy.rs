@@ -1,9 +1,323 @@
-use crate::graph::Graph;
+//! Client-facing graph policies.
+
+use crate::graph::{CincinnatiPayload, Graph};
 use crate::metadata;
+use chrono::{DateTime, Utc};
 use failure::{bail, Fallible};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Default policy chain of the graph-builder.
+pub(crate) static DEFAULT_GB_POLICIES: &[&str] = &["pick_basearch", "filter_deadends"];
+/// Default policy chain of the policy-engine.
+pub(crate) static DEFAULT_PE_POLICIES: &[&str] = &["throttle_rollouts", "trim_to_reachable"];
+/// Policies which must run in the graph-builder, and only there, as the
+/// policy-engine receives graphs with payloads already picked.
+pub(crate) static GB_ONLY_POLICIES: &[&str] = &["pick_basearch"];
+
+/// Record of graph elements dropped by policies, and why.
+///
+/// Recording is disabled by default, so that policies can unconditionally
+/// report their decisions without slowing down regular requests.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Explanation {
+    #[serde(skip)]
+    enabled: bool,
+    pub(crate) dropped_nodes: Vec<DroppedNode>,
+    pub(crate) dropped_edges: Vec<DroppedEdge>,
+}
+
+/// Release dropped from the graph by a policy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DroppedNode {
+    pub(crate) version: String,
+    pub(crate) policy: String,
+    pub(crate) reason: String,
+}
+
+/// Update edge dropped from the graph by a policy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DroppedEdge {
+    pub(crate) from: String,
+    pub(crate) to: String,
+    pub(crate) policy: String,
+    pub(crate) reason: String,
+}
+
+impl Explanation {
+    /// Return an explanation which records policy decisions.
+    pub fn recording() -> Self {
+        Self {
+            enabled: true,
+            ..Self::default()
+        }
+    }
+
+    /// Return an explanation which discards policy decisions.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Record the decisions of `other`, e.g. of policies applied by another service.
+    pub(crate) fn extend(&mut self, other: Explanation) {
+        if !self.enabled {
+            return;
+        }
+        self.dropped_nodes.extend(other.dropped_nodes);
+        self.dropped_edges.extend(other.dropped_edges);
+    }
+
+    fn drop_node<F>(&mut self, node: &CincinnatiPayload, policy: &'static str, reason: F)
+    where
+        F: FnOnce() -> String,
+    {
+        if !self.enabled {
+            return;
+        }
+        self.dropped_nodes.push(DroppedNode {
+            version: node.version.clone(),
+            policy: policy.to_string(),
+            reason: reason(),
+        });
+    }
+
+    fn drop_edge<F>(&mut self, graph: &Graph, edge: (u64, u64), policy: &'static str, reason: F)
+    where
+        F: FnOnce() -> String,
+    {
+        if !self.enabled {
+            return;
+        }
+        let version = |index: u64| graph.nodes[index as usize].version.clone();
+        self.dropped_edges.push(DroppedEdge {
+            from: version(edge.0),
+            to: version(edge.1),
+            policy: policy.to_string(),
+            reason: reason(),
+        });
+    }
+}
+
+/// Graph processed by a policy chain, along with the explanation of its decisions.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ExplainedGraph {
+    #[serde(flatten)]
+    pub(crate) explanation: Explanation,
+    pub(crate) graph: Graph,
+}
+
+/// Client-facing graph policy.
+///
+/// Policies are applied in order by each service, as configured in its
+/// `policies` chain. Site-specific policies can be added by implementing
+/// this trait and registering them in `by_name`.
+pub trait Policy: std::fmt::Debug + Send + Sync {
+    /// Name of this policy, as used in configuration and explanations.
+    fn name(&self) -> &'static str;
+
+    /// Apply this policy to `graph`, for the request described by `ctx`.
+    fn apply(
+        &self,
+        graph: Graph,
+        ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph>;
+}
+
+/// Client request, as seen by policies.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub(crate) stream: String,
+    pub(crate) basearch: String,
+    /// Client parameters, as found in the request query.
+    pub(crate) params: HashMap<String, String>,
+    pub(crate) node_uuid: Option<Uuid>,
+    /// Per-stream salt for client wariness.
+    pub(crate) stream_salt: Option<String>,
+    /// Time at which policies are evaluated.
+    pub(crate) now: DateTime<Utc>,
+}
+
+impl RequestContext {
+    /// Build a request context from client parameters, evaluated at time `now`.
+    pub(crate) fn new(
+        params: HashMap<String, String>,
+        node_uuid: Option<Uuid>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let param = |name| params.get(name).cloned().unwrap_or_default();
+        Self {
+            stream: param("stream"),
+            basearch: param("basearch"),
+            params,
+            node_uuid,
+            stream_salt: None,
+            now,
+        }
+    }
+
+    /// Compute client wariness for a rollout, given its specific salt (if any).
+    pub(crate) fn wariness(&self, rollout_salt: Option<&str>) -> f64 {
+        let salt = rollout_salt.or_else(|| self.stream_salt.as_ref().map(String::as_str));
+        compute_wariness(&self.params, self.node_uuid.as_ref(), salt)
+    }
+
+    /// Return the version currently running on the client, if known.
+    pub(crate) fn client_version(&self) -> Option<ClientVersion> {
+        ClientVersion::from_params(&self.params)
+    }
+}
+
+/// Ordered chain of policies.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyChain {
+    policies: Vec<Arc<dyn Policy>>,
+}
+
+impl PolicyChain {
+    /// Build a chain from policy names, in order.
+    pub(crate) fn from_names<S: AsRef<str>>(names: &[S]) -> Fallible<Self> {
+        let mut policies = Vec::with_capacity(names.len());
+        for name in names {
+            let name = name.as_ref();
+            match by_name(name) {
+                Some(policy) => policies.push(policy),
+                None => bail!("unknown policy '{}'", name),
+            }
+        }
+        Ok(Self { policies })
+    }
+
+    /// Apply all policies in order.
+    pub(crate) fn apply(
+        &self,
+        graph: Graph,
+        ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph> {
+        let mut graph = graph;
+        for policy in &self.policies {
+            graph = policy.apply(graph, ctx, explain)?;
+        }
+        Ok(graph)
+    }
+}
+
+impl std::fmt::Display for PolicyChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<_> = self.policies.iter().map(|p| p.name()).collect();
+        write!(f, "[{}]", names.join(", "))
+    }
+}
+
+/// Look up a policy by name.
+pub(crate) fn by_name(name: &str) -> Option<Arc<dyn Policy>> {
+    let policy: Arc<dyn Policy> = match name {
+        "pick_basearch" => Arc::new(PickBasearch {}),
+        "filter_deadends" => Arc::new(FilterDeadends {}),
+        "throttle_rollouts" => Arc::new(ThrottleRollouts {}),
+        "trim_to_reachable" => Arc::new(TrimToReachable {}),
+        _ => return None,
+    };
+    Some(policy)
+}
+
+/// Policy selecting payloads for the requested basearch, see `pick_basearch`.
+#[derive(Debug)]
+struct PickBasearch {}
+
+impl Policy for PickBasearch {
+    fn name(&self) -> &'static str {
+        "pick_basearch"
+    }
+
+    fn apply(
+        &self,
+        graph: Graph,
+        ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph> {
+        pick_basearch(graph, ctx.basearch.clone(), explain)
+    }
+}
+
+/// Policy pruning updates from dead-ends, see `filter_deadends`.
+#[derive(Debug)]
+struct FilterDeadends {}
+
+impl Policy for FilterDeadends {
+    fn name(&self) -> &'static str {
+        "filter_deadends"
+    }
+
+    fn apply(
+        &self,
+        graph: Graph,
+        _ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph> {
+        Ok(filter_deadends(graph, explain))
+    }
+}
+
+/// Policy throttling rollouts by client wariness, see `throttle_rollouts`.
+#[derive(Debug)]
+struct ThrottleRollouts {}
+
+impl Policy for ThrottleRollouts {
+    fn name(&self) -> &'static str {
+        "throttle_rollouts"
+    }
+
+    fn apply(
+        &self,
+        graph: Graph,
+        ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph> {
+        let now = ctx.now.timestamp();
+        Ok(throttle_rollouts(
+            graph,
+            now,
+            |salt| ctx.wariness(salt),
+            explain,
+        ))
+    }
+}
+
+/// Policy trimming the graph to releases reachable from the client
+/// release, if any, see `trim_to_reachable`.
+///
+/// Client releases not in the graph are errors, as nothing could be trimmed.
+#[derive(Debug)]
+struct TrimToReachable {}
+
+impl Policy for TrimToReachable {
+    fn name(&self) -> &'static str {
+        "trim_to_reachable"
+    }
+
+    fn apply(
+        &self,
+        graph: Graph,
+        ctx: &RequestContext,
+        explain: &mut Explanation,
+    ) -> Fallible<Graph> {
+        let client = match ctx.client_version() {
+            Some(client) => client,
+            None => return Ok(graph),
+        };
+        match client.find_in(&graph) {
+            Some(index) => Ok(trim_to_reachable(graph, index, explain)),
+            None => bail!("unknown client release '{}'", client),
+        }
+    }
+}
 
 /// Prune outgoing edges from "deadend" nodes.
-pub fn filter_deadends(input: Graph) -> Graph {
+pub fn filter_deadends(input: Graph, explain: &mut Explanation) -> Graph {
     use std::collections::HashSet;
 
     let mut graph = input;
@@ -15,6 +329,19 @@ pub fn filter_deadends(input: Graph) -> Graph {
         }
     }
 
+    for edge in &graph.edges {
+        let release = &graph.nodes[edge.0 as usize];
+        if deadends.contains(&(edge.0 as usize)) {
+            explain.drop_edge(&graph, *edge, "filter_deadends", || {
+                let reason = release
+                    .metadata
+                    .get(metadata::DEADEND_REASON)
+                    .map(String::as_str)
+                    .unwrap_or("generic");
+                format!("source release is a dead-end ({})", reason)
+            });
+        }
+    }
     graph.edges.retain(|(from, _to)| {
         let index = *from as usize;
         !deadends.contains(&index)
@@ -24,94 +351,739 @@ pub fn filter_deadends(input: Graph) -> Graph {
     graph
 }
 
+/// Check that a requested basearch is one payloads can be served for.
+pub(crate) fn check_basearch(basearch: &str) -> Result<(), String> {
+    if basearch.is_empty() {
+        return Err("missing basearch".to_string());
+    }
+    if !metadata::BASEARCHES.contains(&basearch) {
+        return Err(format!("unexpected basearch '{}'", basearch));
+    }
+    Ok(())
+}
+
 /// Pick relevant payload for requested basearch.
-pub fn pick_basearch(input: Graph, basearch: String) -> Fallible<Graph> {
-    let mut graph = input;
-    let key = format!("{}.{}", metadata::ARCH_PREFIX, &basearch);
+///
+/// Releases without a payload for `basearch` are dropped from the graph,
+/// and edges are re-indexed accordingly. Barriers cannot be dropped, as
+/// clients could then no longer update past them, so a barrier without a
+/// payload is an error.
+pub fn pick_basearch(input: Graph, basearch: String, explain: &mut Explanation) -> Fallible<Graph> {
+    use std::collections::HashSet;
 
-    if basearch != "x86_64" {
-        bail!("unexpected basearch '{}", basearch);
+    if let Err(e) = check_basearch(&basearch) {
+        bail!("{}", e);
     }
 
-    for mut release in &mut graph.nodes {
-        if let Some(payload) = release.metadata.remove(&key) {
-            release.payload = payload;
-            release
-                .metadata
-                .insert(metadata::SCHEME.to_string(), "checksum".to_string());
-        }
+    let key = format!("{}.{}", metadata::ARCH_PREFIX, &basearch);
+    let mut graph = input;
+
+    let mut keep = HashSet::with_capacity(graph.nodes.len());
+    for (index, release) in graph.nodes.iter_mut().enumerate() {
+        let payload = match release.metadata.remove(&key) {
+            Some(payload) => payload,
+            None if release.metadata.get(metadata::BARRIER) == Some(&"true".into()) => bail!(
+                "barrier release '{}' has no payload for basearch '{}'",
+                release.version,
+                basearch
+            ),
+            None => {
+                explain.drop_node(release, "pick_basearch", || {
+                    format!("no payload for basearch '{}'", basearch)
+                });
+                continue;
+            }
+        };
+        release.payload = payload;
+        release
+            .metadata
+            .insert(metadata::SCHEME.to_string(), "checksum".to_string());
         release
             .metadata
             .retain(|k, _| !k.starts_with(metadata::ARCH_PREFIX));
+        keep.insert(index);
     }
 
-    Ok(graph)
+    Ok(retain_nodes(graph, &keep, "pick_basearch", explain))
 }
 
-/// Conditionally prune incoming edges towards throttled rollouts.
-pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
-    use std::collections::HashSet;
+/// Trim the graph to the nodes reachable from `current`, plus `current` itself.
+pub fn trim_to_reachable(input: Graph, current: usize, explain: &mut Explanation) -> Graph {
+    use std::collections::{HashMap, HashSet};
+
+    let mut successors: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (from, to) in &input.edges {
+        successors
+            .entry(*from as usize)
+            .or_default()
+            .push(*to as usize);
+    }
+
+    let mut reachable = HashSet::new();
+    let mut pending = vec![current];
+    while let Some(index) = pending.pop() {
+        if !reachable.insert(index) {
+            continue;
+        }
+        if let Some(next) = successors.get(&index) {
+            pending.extend(next);
+        }
+    }
+
+    if let Some(client) = input.nodes.get(current) {
+        for (index, release) in input.nodes.iter().enumerate() {
+            if !reachable.contains(&index) {
+                explain.drop_node(release, "trim_to_reachable", || {
+                    format!("not reachable from client release '{}'", client.version)
+                });
+            }
+        }
+    }
+
+    retain_nodes(input, &reachable, "trim_to_reachable", explain)
+}
+
+/// Drop all nodes not in `keep`, re-indexing edges accordingly.
+///
+/// Edges from or to dropped nodes are reported as dropped by `policy`.
+fn retain_nodes(
+    input: Graph,
+    keep: &std::collections::HashSet<usize>,
+    policy: &'static str,
+    explain: &mut Explanation,
+) -> Graph {
+    use std::collections::HashMap;
 
     let mut graph = input;
-    let mut hidden = HashSet::new();
-    let now = chrono::Utc::now().timestamp();
 
-    for (index, release) in graph.nodes.iter().enumerate() {
-        // Skip if this release is not being rolled out.
-        if release.metadata.get(metadata::ROLLOUT).is_none() {
+    for edge in &graph.edges {
+        let (from, to) = (edge.0 as usize, edge.1 as usize);
+        if !keep.contains(&from) || !keep.contains(&to) {
+            explain.drop_edge(&graph, *edge, policy, || {
+                let side = if keep.contains(&from) {
+                    "target"
+                } else {
+                    "source"
+                };
+                format!("{} release dropped", side)
+            });
+        }
+    }
+
+    // Map old node indices to new ones, for nodes which are kept.
+    let mut reindex = HashMap::with_capacity(keep.len());
+    let mut nodes = Vec::with_capacity(keep.len());
+    for (index, release) in graph.nodes.drain(..).enumerate() {
+        if !keep.contains(&index) {
             continue;
-        };
+        }
+        reindex.insert(index as u64, nodes.len() as u64);
+        nodes.push(release);
+    }
+    graph.nodes = nodes;
 
-        // Start epoch defaults to 0.
-        let start_epoch = match release.metadata.get(metadata::START_EPOCH) {
-            Some(epoch) => epoch.parse::<i64>().unwrap_or(0),
-            None => 0i64,
-        };
+    graph.edges = graph
+        .edges
+        .into_iter()
+        .filter_map(|(from, to)| match (reindex.get(&from), reindex.get(&to)) {
+            (Some(new_from), Some(new_to)) => Some((*new_from, *new_to)),
+            _ => None,
+        })
+        .collect();
+
+    graph
+}
+
+/// Conditionally prune incoming edges towards throttled rollouts, at time `now`
+/// (seconds since the Unix epoch).
+///
+/// `client_wariness` computes the client wariness for a rollout, given
+/// the rollout-specific salt (if any).
+pub fn throttle_rollouts<F>(
+    input: Graph,
+    now: i64,
+    client_wariness: F,
+    explain: &mut Explanation,
+) -> Graph
+where
+    F: Fn(Option<&str>) -> f64,
+{
+    use std::collections::HashMap;
+
+    let mut graph = input;
+    let mut hidden = HashMap::new();
 
-        // Start value defaults to 0.0.
-        let start_value = match release.metadata.get(metadata::START_VALUE) {
-            Some(val) => val.parse::<f64>().unwrap_or(0f64),
-            None => 0f64,
+    for (index, release) in graph.nodes.iter().enumerate() {
+        // Skip if this release is not being rolled out.
+        if !release.metadata.contains_key(metadata::ROLLOUT) {
+            continue;
         };
 
-        // Duration has no default (i.e. no progress).
-        let mut minutes: Option<u64> = None;
-        if let Some(mins) = release.metadata.get(metadata::DURATION) {
-            if let Ok(m) = mins.parse::<u64>() {
-                minutes = Some(m.max(1));
-            }
+        // Aborted rollouts are hidden from all clients.
+        if release.metadata.contains_key(metadata::ROLLOUT_ABORTED) {
+            hidden.insert(index, None);
+            continue;
         }
 
-        let throttling: f64;
-        if let Some(mins) = minutes {
-            let end = start_epoch + (mins.saturating_mul(60)) as i64;
-            let rate = (1.0 - start_value) / (end.saturating_sub(start_epoch)) as f64;
-            if now < start_epoch {
-                throttling = 0.0;
-            } else if now > end {
-                throttling = 1.0;
-            } else {
-                throttling = start_value + rate * (now - start_epoch) as f64;
-            }
-        } else {
-            // Without duration, rollout does not progress past initial value.
-            if now < start_epoch {
-                throttling = 0.0;
-            } else {
-                throttling = start_value
-            }
-        }
+        let throttling = rollout_throttling(&release.metadata, now);
 
-        if client_wariness > throttling {
-            hidden.insert(index);
+        let salt = release
+            .metadata
+            .get(metadata::ROLLOUT_SALT)
+            .map(String::as_str);
+        let wariness = client_wariness(salt);
+        if wariness > throttling {
+            hidden.insert(index, Some((throttling, wariness)));
         }
     }
 
+    for edge in &graph.edges {
+        if let Some(throttled) = hidden.get(&(edge.1 as usize)) {
+            explain.drop_edge(&graph, *edge, "throttle_rollouts", || match throttled {
+                Some((throttling, wariness)) => format!(
+                    "target release is throttled: rollout at {:.6}, below client wariness {:.6}",
+                    throttling, wariness
+                ),
+                None => "target release rollout is aborted".to_string(),
+            });
+        }
+    }
     graph.edges.retain(|(_from, to)| {
         let index = *to as usize;
-        !hidden.contains(&index)
+        !hidden.contains_key(&index)
     });
     graph.edges.shrink_to_fit();
 
     graph
 }
+
+/// Compute the throttling value of a rollout at time `now`, in the range [0.0, 1.0].
+///
+/// Before its start epoch, a rollout is fully throttled. It then progresses from
+/// its start value according to its curve:
+///  * linear (default): linear ramp up to 1.0 over its duration
+///  * exponential: geometric growth up to 1.0 over its duration, starting from
+///    at least `MIN_EXPONENTIAL_START`
+///  * stepped: value of the latest step reached, duration is ignored
+///
+/// Without duration (or steps), a rollout does not progress past its start
+/// value. A paused rollout does not progress past the value it had when paused.
+/// Unknown curves are rejected when validating upstream metadata.
+pub(crate) fn rollout_throttling(params: &HashMap<String, String>, now: i64) -> f64 {
+    /// Lowest initial value for exponential curves.
+    const MIN_EXPONENTIAL_START: f64 = 0.01;
+
+    let now = match params
+        .get(metadata::ROLLOUT_PAUSED)
+        .and_then(|epoch| epoch.parse::<i64>().ok())
+    {
+        Some(paused) => now.min(paused),
+        None => now,
+    };
+
+    // Start epoch defaults to 0.
+    let start_epoch = match params.get(metadata::START_EPOCH) {
+        Some(epoch) => epoch.parse::<i64>().unwrap_or(0),
+        None => 0i64,
+    };
+    if now < start_epoch {
+        return 0.0;
+    }
+    let elapsed_secs = now - start_epoch;
+
+    // Start value defaults to 0.0.
+    let start_value = match params.get(metadata::START_VALUE) {
+        Some(val) => val.parse::<f64>().unwrap_or(0f64),
+        None => 0f64,
+    };
+    let start_value = start_value.max(0.0).min(1.0);
+
+    // Duration has no default (i.e. no progress).
+    let mut minutes: Option<u64> = None;
+    if let Some(mins) = params.get(metadata::DURATION) {
+        if let Ok(m) = mins.parse::<u64>() {
+            minutes = Some(m.max(1));
+        }
+    }
+
+    let curve = params
+        .get(metadata::ROLLOUT_CURVE)
+        .map(String::as_str)
+        .unwrap_or(metadata::CURVE_LINEAR);
+    if curve == metadata::CURVE_STEPPED {
+        let steps = params
+            .get(metadata::ROLLOUT_STEPS)
+            .map(|s| metadata::parse_rollout_steps(s))
+            .unwrap_or_default();
+        let elapsed_minutes = (elapsed_secs / 60) as u64;
+        let reached = steps
+            .iter()
+            .take_while(|(offset, _)| *offset <= elapsed_minutes)
+            .last();
+        return match reached {
+            Some((_, value)) => value.max(0.0).min(1.0),
+            None => start_value,
+        };
+    }
+
+    let progress = match minutes {
+        Some(mins) => {
+            let duration_secs = mins.saturating_mul(60) as f64;
+            (elapsed_secs as f64 / duration_secs).min(1.0)
+        }
+        None => return start_value,
+    };
+    match curve {
+        metadata::CURVE_LINEAR => start_value + (1.0 - start_value) * progress,
+        metadata::CURVE_EXPONENTIAL => {
+            let initial = start_value.max(MIN_EXPONENTIAL_START);
+            initial.powf(1.0 - progress)
+        }
+        _ => start_value,
+    }
+}
+
+/// Version currently running on a client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ClientVersion {
+    version: Option<String>,
+    checksum: Option<String>,
+}
+
+impl ClientVersion {
+    /// Parse the `os_version` and `os_checksum` client parameters, if any.
+    pub(crate) fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        let param = |name| params.get(name).filter(|v| !v.is_empty()).cloned();
+        let client = Self {
+            version: param("os_version"),
+            checksum: param("os_checksum"),
+        };
+        if client == Self::default() {
+            return None;
+        }
+        Some(client)
+    }
+
+    /// Find the index of the matching node in `graph`, if any.
+    pub(crate) fn find_in(&self, graph: &Graph) -> Option<usize> {
+        graph.nodes.iter().position(|node| {
+            self.version.as_ref().map_or(true, |v| v == &node.version)
+                && self.checksum.as_ref().map_or(true, |c| c == &node.payload)
+        })
+    }
+}
+
+impl std::fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.version, &self.checksum) {
+            (Some(version), Some(checksum)) => write!(f, "{} ({})", version, checksum),
+            (Some(version), None) => write!(f, "{}", version),
+            (None, Some(checksum)) => write!(f, "{}", checksum),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Compute a stable digest of a node UUID, with an optional salt.
+///
+/// This is the first 8 bytes (big-endian) of the SHA-256 of the salt followed
+/// by the 16 raw bytes of the UUID, so it does not depend on how the client
+/// formatted it, nor on the toolchain this service was built with.
+pub(crate) fn node_digest(id: &Uuid, salt: &str) -> u64 {
+    id_digest(id.as_bytes(), salt)
+}
+
+/// Compute a stable digest of raw node ID bytes, see `node_digest`.
+fn id_digest(id: &[u8], salt: &str) -> u64 {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.input(salt.as_bytes());
+    hasher.input(id);
+    let hash = hasher.result();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// Compute client rollout wariness, in the range (0.0, 1.0].
+///
+/// An explicit `rollout_wariness` parameter takes precedence. Otherwise it is
+/// derived from the node UUID and the (stream or rollout) salt; clients
+/// without a UUID all share the wariness of an empty ID.
+pub(crate) fn compute_wariness(
+    params: &HashMap<String, String>,
+    node_uuid: Option<&Uuid>,
+    salt: Option<&str>,
+) -> f64 {
+    if let Ok(input) = params
+        .get("rollout_wariness")
+        .map(String::from)
+        .unwrap_or_default()
+        .parse::<f64>()
+    {
+        let wariness = input.max(0.0).min(1.0);
+        return wariness;
+    }
+
+    let salt = salt.unwrap_or_default();
+    let digest = match node_uuid {
+        Some(id) => node_digest(id, salt),
+        None => id_digest(&[], salt),
+    };
+
+    // Left limit not included in range.
+    const COMPUTED_MIN: f64 = 0.0 + 0.000001;
+    const COMPUTED_MAX: f64 = 1.0;
+    // Scale down.
+    let scaled = (digest as f64) / (std::u64::MAX as f64);
+    // Clamp within limits.
+    scaled.max(COMPUTED_MIN).min(COMPUTED_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::graph_with_markers;
+    use chrono::TimeZone;
+
+    /// Time at which policies are evaluated.
+    const NOW: i64 = 1_570_000_000;
+
+    fn sorted(mut edges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn deadends_pruned_by_policy() {
+        let graph = graph_with_markers(&["", "deadend", "barrier", "deadend", ""]);
+        let graph = filter_deadends(graph, &mut Explanation::disabled());
+        let expected = vec![(0, 1), (0, 2), (2, 3), (2, 4)];
+        assert_eq!(sorted(graph.edges), expected);
+    }
+
+    #[test]
+    fn rollouts_throttled_by_policy() {
+        let graph = graph_with_markers(&["", "barrier", "", "rollout"]);
+        let graph = throttle_rollouts(graph, NOW, |_| 1.0, &mut Explanation::disabled());
+        let expected = vec![(0, 1), (1, 2)];
+        assert_eq!(sorted(graph.edges), expected);
+    }
+
+    #[test]
+    fn policy_decisions_explained() {
+        let graph = graph_with_markers(&["", "deadend", "barrier", "rollout"]);
+        let mut explanation = Explanation::recording();
+        let graph = pick_basearch(graph, "x86_64".to_string(), &mut explanation).unwrap();
+        let graph = filter_deadends(graph, &mut explanation);
+
+        // Graph-builder decisions are passed along to the policy-engine.
+        let explained = serde_json::to_string(&ExplainedGraph { explanation, graph }).unwrap();
+        let explained: ExplainedGraph = serde_json::from_str(&explained).unwrap();
+        let mut explain = Explanation::recording();
+        explain.extend(explained.explanation);
+        let graph = throttle_rollouts(explained.graph, NOW, |_| 0.5, &mut explain);
+        let graph = trim_to_reachable(graph, 2, &mut explain);
+        assert_eq!(graph.nodes.len(), 1);
+        assert!(graph.edges.is_empty());
+
+        let dropped: Vec<_> = explain
+            .dropped_edges
+            .iter()
+            .map(|e| (e.from.as_str(), e.to.as_str(), e.policy.as_str()))
+            .collect();
+        assert_eq!(
+            dropped,
+            vec![
+                ("30.1", "30.2", "filter_deadends"),
+                ("30.2", "30.3", "throttle_rollouts"),
+                ("30.0", "30.1", "trim_to_reachable"),
+                ("30.0", "30.2", "trim_to_reachable"),
+            ]
+        );
+        let dropped: Vec<_> = explain
+            .dropped_nodes
+            .iter()
+            .map(|n| (n.version.as_str(), n.policy.as_str()))
+            .collect();
+        assert_eq!(
+            dropped,
+            vec![
+                ("30.0", "trim_to_reachable"),
+                ("30.1", "trim_to_reachable"),
+                ("30.3", "trim_to_reachable"),
+            ]
+        );
+
+        let mut explain = Explanation::recording();
+        let graph = graph_with_markers(&[""]);
+        let graph = pick_basearch(graph, "s390x".to_string(), &mut explain).unwrap();
+        assert!(graph.nodes.is_empty());
+        assert_eq!(explain.dropped_nodes[0].policy, "pick_basearch");
+    }
+
+    #[test]
+    fn basearch_validation() {
+        assert!(check_basearch("x86_64").is_ok());
+        assert!(check_basearch("aarch64").is_ok());
+        assert_eq!(check_basearch("").unwrap_err(), "missing basearch");
+        assert_eq!(
+            check_basearch("i686").unwrap_err(),
+            "unexpected basearch 'i686'"
+        );
+
+        let graph = graph_with_markers(&[""]);
+        assert!(pick_basearch(graph, "i686".to_string(), &mut Explanation::disabled()).is_err());
+    }
+
+    #[test]
+    fn policy_chains() {
+        assert!(PolicyChain::from_names(&["filter_deadends", "foo"]).is_err());
+
+        let gb = PolicyChain::from_names(DEFAULT_GB_POLICIES).unwrap();
+        let pe = PolicyChain::from_names(DEFAULT_PE_POLICIES).unwrap();
+        assert_eq!(gb.to_string(), "[pick_basearch, filter_deadends]");
+
+        let params = hashmap! {
+            "basearch".to_string() => "x86_64".to_string(),
+            "os_version".to_string() => "30.2".to_string(),
+            "rollout_wariness".to_string() => "0.5".to_string(),
+        };
+        let now = chrono::Utc.timestamp(NOW, 0);
+        let ctx = RequestContext::new(params, None, now);
+        let graph = graph_with_markers(&["", "deadend", "", "", "rollout"]);
+        let mut explain = Explanation::disabled();
+        let graph = gb.apply(graph, &ctx, &mut explain).unwrap();
+        let graph = pe.apply(graph, &ctx, &mut explain).unwrap();
+        let versions: Vec<_> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["30.2", "30.3"]);
+        assert_eq!(graph.nodes[0].payload, "sha2");
+        assert_eq!(graph.edges, vec![(0, 1)]);
+
+        let params = hashmap! {
+            "basearch".to_string() => "x86_64".to_string(),
+            "os_version".to_string() => "29.0".to_string(),
+        };
+        let ctx = RequestContext::new(params, None, now);
+        let graph = graph_with_markers(&["", ""]);
+        pe.apply(graph, &ctx, &mut explain).unwrap_err();
+    }
+
+    #[test]
+    fn rollout_curves() {
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [
+                { "version": "30.0", "metadata": { "rollout": {
+                    "start_epoch": 1000, "start_percentage": 0.1, "duration_minutes": 100,
+                } } },
+                { "version": "30.1", "metadata": { "rollout": {
+                    "start_epoch": 1000, "start_percentage": 0.01, "duration_minutes": 100,
+                    "curve": "exponential",
+                } } },
+                { "version": "30.2", "metadata": { "rollout": {
+                    "start_epoch": 1000, "curve": "stepped", "steps": [
+                        { "offset_minutes": 1440, "percentage": 0.1 },
+                        { "offset_minutes": 0, "percentage": 0.01 },
+                        { "offset_minutes": 2880, "percentage": 1.0 },
+                    ],
+                } } },
+            ],
+        }))
+        .unwrap();
+        let releases = metadata::test_releases(&["30.0", "30.1", "30.2"]);
+        let graph = Graph::from_metadata(releases, updates).unwrap();
+        assert_eq!(
+            graph.nodes[2].metadata[metadata::ROLLOUT_STEPS],
+            "0=0.01,1440=0.1,2880=1"
+        );
+
+        let throttling = |index: usize, minutes: i64| {
+            rollout_throttling(&graph.nodes[index].metadata, 1000 + minutes * 60)
+        };
+        let cases = [
+            // Linear.
+            (0, -1, 0.0),
+            (0, 0, 0.1),
+            (0, 50, 0.55),
+            (0, 100, 1.0),
+            (0, 200, 1.0),
+            // Exponential.
+            (1, -1, 0.0),
+            (1, 0, 0.01),
+            (1, 50, 0.1),
+            (1, 100, 1.0),
+            // Stepped.
+            (2, -1, 0.0),
+            (2, 0, 0.01),
+            (2, 1439, 0.01),
+            (2, 1440, 0.1),
+            (2, 2880, 1.0),
+        ];
+        for (index, minutes, expected) in &cases {
+            let value = throttling(*index, *minutes);
+            assert!(
+                (value - expected).abs() < 1e-9,
+                "30.{} at {}m: {}",
+                index,
+                minutes,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rollout_pause_and_abort() {
+        let rollout = serde_json::json!({
+            "start_epoch": 0, "start_percentage": 0.0, "duration_minutes": 100,
+        });
+        let mut paused = rollout.clone();
+        paused["state"] = "paused".into();
+        paused["paused_epoch"] = 1500.into();
+        let mut aborted = rollout.clone();
+        aborted["state"] = "aborted".into();
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [
+                { "version": "30.1", "metadata": { "rollout": paused } },
+                { "version": "30.2", "metadata": { "rollout": aborted } },
+                { "version": "30.3", "metadata": { "rollout": rollout } },
+            ],
+        }))
+        .unwrap();
+        let releases = metadata::test_releases(&["30.0", "30.1", "30.2", "30.3"]);
+        let mut graph = Graph::from_metadata(releases, updates).unwrap();
+
+        // Paused upstream, frozen at 25%.
+        let value = rollout_throttling(&graph.nodes[1].metadata, 6000);
+        assert!((value - 0.25).abs() < 1e-9, "{}", value);
+
+        // Aborted upstream, hidden even from the least wary clients.
+        let throttled =
+            throttle_rollouts(graph.clone(), NOW, |_| 0.0, &mut Explanation::disabled());
+        let targets: Vec<_> = throttled.edges.iter().map(|(_, to)| *to).collect();
+        assert!(!targets.contains(&2));
+        assert!(targets.contains(&3));
+
+        // Paused locally, pinned to when it was first seen paused.
+        let previous = graph.clone();
+        graph.apply_rollout_overrides(&hashmap! {
+            "30.0".to_string() => "paused".to_string(),
+            "30.1".to_string() => "active".to_string(),
+            "30.3".to_string() => "paused".to_string(),
+        });
+        assert!(!graph.nodes[0]
+            .metadata
+            .contains_key(metadata::ROLLOUT_PAUSED));
+        assert!(!graph.nodes[1]
+            .metadata
+            .contains_key(metadata::ROLLOUT_PAUSED));
+        graph.pin_paused_rollouts(&previous, 3000);
+        assert_eq!(graph.nodes[3].metadata[metadata::ROLLOUT_PAUSED], "3000");
+        let mut next = graph.clone();
+        next.nodes[3]
+            .metadata
+            .insert(metadata::ROLLOUT_PAUSED.to_string(), "".to_string());
+        next.pin_paused_rollouts(&graph, 4500);
+        assert_eq!(next.nodes[3].metadata[metadata::ROLLOUT_PAUSED], "3000");
+        let value = rollout_throttling(&next.nodes[3].metadata, 4500);
+        assert!((value - 0.5).abs() < 1e-9, "{}", value);
+    }
+
+    #[test]
+    fn trimmed_to_reachable_by_policy() {
+        let graph = graph_with_markers(&["", "barrier", "", "deadend", ""]);
+        let graph = filter_deadends(graph, &mut Explanation::disabled());
+        let graph = trim_to_reachable(graph, 2, &mut Explanation::disabled());
+        let versions: Vec<_> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["30.2", "30.3", "30.4"]);
+        assert_eq!(sorted(graph.edges), vec![(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn basearch_selection() {
+        let releases: Vec<metadata::Release> = serde_json::from_value(serde_json::json!([
+            { "version": "30.0", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "x0" },
+                { "architecture": "aarch64", "checksum": "a0" },
+            ] },
+            { "version": "30.1", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "x1" },
+            ] },
+            { "version": "30.2", "metadata": "", "commits": [
+                { "architecture": "aarch64", "checksum": "a2" },
+                { "architecture": "x86_64", "checksum": "x2" },
+                { "architecture": "s390x", "checksum": "s2" },
+            ] },
+        ]))
+        .unwrap();
+        let updates = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [],
+        }))
+        .unwrap();
+        let mut graph = Graph::from_metadata(releases, updates).unwrap();
+        graph.edges = vec![(0, 1), (0, 2), (1, 2)];
+
+        let picked = |basearch: &str| {
+            let graph = pick_basearch(
+                graph.clone(),
+                basearch.to_string(),
+                &mut Explanation::disabled(),
+            )
+            .unwrap();
+            for node in &graph.nodes {
+                assert_eq!(node.metadata[metadata::SCHEME], "checksum");
+                assert!(!node
+                    .metadata
+                    .keys()
+                    .any(|k| k.starts_with(metadata::ARCH_PREFIX)));
+            }
+            let nodes: Vec<_> = graph
+                .nodes
+                .iter()
+                .map(|n| (n.version.clone(), n.payload.clone()))
+                .collect();
+            (nodes, graph.edges)
+        };
+        let pair = |version: &str, payload: &str| (version.to_string(), payload.to_string());
+
+        assert_eq!(
+            picked("x86_64"),
+            (
+                vec![pair("30.0", "x0"), pair("30.1", "x1"), pair("30.2", "x2")],
+                vec![(0, 1), (0, 2), (1, 2)]
+            )
+        );
+        assert_eq!(
+            picked("aarch64"),
+            (vec![pair("30.0", "a0"), pair("30.2", "a2")], vec![(0, 1)])
+        );
+        assert_eq!(picked("s390x"), (vec![pair("30.2", "s2")], vec![]));
+        assert_eq!(picked("ppc64le"), (vec![], vec![]));
+        assert!(pick_basearch(
+            graph.clone(),
+            "i686".to_string(),
+            &mut Explanation::disabled()
+        )
+        .is_err());
+
+        // Barriers missing a payload cannot be dropped.
+        graph.nodes[1]
+            .metadata
+            .insert(metadata::BARRIER.to_string(), "true".to_string());
+        let err = pick_basearch(
+            graph.clone(),
+            "aarch64".to_string(),
+            &mut Explanation::disabled(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "barrier release '30.1' has no payload for basearch 'aarch64'"
+        );
+        let graph = pick_basearch(graph, "x86_64".to_string(), &mut Explanation::disabled());
+        assert_eq!(graph.unwrap().nodes.len(), 3);
+    }
+}
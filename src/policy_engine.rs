@@ -0,0 +1,511 @@
+//! Policy-engine service.
+
+use crate::clock::{self, Clock};
+use crate::graph_client::{CheckReady, GetExplainedGraph, GetGraph, GraphClient};
+use crate::{config, graph, health, metrics, negotiate, policy};
+use actix::prelude::*;
+use actix_web::{http::Method, middleware::Logger, server, App};
+use actix_web::{HttpRequest, HttpResponse};
+use failure::{Error, Fallible};
+use futures::future;
+use futures::prelude::*;
+use prometheus::{Histogram, IntCounter, IntCounterVec};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Request header carrying the admin token.
+static ADMIN_TOKEN_HEADER: &str = "X-Dumnati-Admin-Token";
+
+lazy_static::lazy_static! {
+    static ref V1_GRAPH_INCOMING_REQS: IntCounter = register_int_counter!(opts!(
+        "dumnati_pe_v1_graph_incoming_requests_total",
+        "Total number of incoming HTTP client request to /v1/graph"
+    ))
+    .unwrap();
+    static ref UNIQUE_IDS: IntCounter = register_int_counter!(opts!(
+        "dumnati_pe_v1_graph_unique_uuids_total",
+        "Total number of unique node UUIDs (per-instance Bloom filter)."
+    ))
+    .unwrap();
+    static ref ROLLOUT_WARINESS: Histogram = register_histogram!(
+        "dumnati_pe_v1_graph_rollout_wariness",
+        "Per-request rollout wariness.",
+        prometheus::linear_buckets(0.0, 0.1, 11).unwrap()
+    )
+    .unwrap();
+    static ref V1_PATH_INCOMING_REQS: IntCounter = register_int_counter!(opts!(
+        "dumnati_pe_v1_path_incoming_requests_total",
+        "Total number of incoming HTTP client request to /v1/path"
+    ))
+    .unwrap();
+    static ref UNKNOWN_VERSIONS: IntCounterVec = register_int_counter_vec!(
+        "dumnati_pe_v1_graph_unknown_versions_total",
+        "Total number of requests from clients running a version not in the graph",
+        &["stream"]
+    )
+    .unwrap();
+}
+
+/// Run the policy-engine service, fetching graphs from the graph-builder.
+pub(crate) fn run(settings: config::Settings) -> Fallible<()> {
+    let sys = actix::System::new("dumnati-policy-engine");
+
+    let graph_client =
+        GraphClient::new(&settings.policy_engine, &settings.upstream.streams)?.start();
+
+    let node_population = Arc::new(cbloom::Filter::new(10 * 1024 * 1024, 1_000_000));
+    let service_state = AppState {
+        graph_client,
+        population: Arc::clone(&node_population),
+        rollout_salts: Arc::new(settings.policy_engine.rollout_salts.clone()),
+        policies: Arc::new(settings.policy_engine.policies.clone()),
+        admin_token: settings.policy_engine.admin_token.clone(),
+        clock: Arc::new(clock::SystemClock::default()),
+    };
+    log::info!("policy chain: {}", service_state.policies);
+    let pe_service = service_state.clone();
+    let pe_status = service_state.clone();
+    let cfg = &settings.policy_engine.service;
+    let enable_explain = settings.policy_engine.enable_explain;
+
+    // Policy-engine service.
+    server::new(move || {
+        let app = App::with_state(pe_service.clone())
+            .middleware(Logger::default())
+            .route("/v1/graph", Method::GET, pe_serve_graph)
+            .route("/v1/path", Method::GET, pe_serve_path);
+        if enable_explain {
+            app.route("/v1/explain", Method::GET, pe_serve_explain)
+        } else {
+            app
+        }
+    })
+    .bind((cfg.address, cfg.port))?
+    .start();
+
+    // Policy-engine status service.
+    server::new(move || {
+        App::with_state(pe_status.clone())
+            .middleware(Logger::default())
+            .route("/metrics", Method::GET, metrics::serve_metrics)
+            .route("/health/live", Method::GET, health::serve_live)
+            .route("/health/ready", Method::GET, pe_serve_ready)
+    })
+    .bind((cfg.address, cfg.status_port))?
+    .start();
+
+    sys.run();
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AppState {
+    graph_client: Addr<GraphClient>,
+    population: Arc<cbloom::Filter>,
+    rollout_salts: Arc<HashMap<String, String>>,
+    policies: Arc<policy::PolicyChain>,
+    admin_token: Option<config::Secret>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AppState {
+    /// Build the policy context for a client request, rejecting unknown basearches.
+    ///
+    /// Policies are evaluated at the current time, unless overridden by an
+    /// admin via the `now` parameter.
+    fn request_context(
+        &self,
+        req: &HttpRequest<AppState>,
+        node_uuid: Option<Uuid>,
+    ) -> Result<policy::RequestContext, HttpResponse> {
+        let now = match req.query().get("now").filter(|v| !v.is_empty()) {
+            Some(input) => {
+                if !self.is_admin(req) {
+                    return Err(crate::forbidden("parameter 'now' requires an admin token"));
+                }
+                clock::parse_time(input).map_err(|e| crate::invalid_param("now", &e))?
+            }
+            None => self.clock.now(),
+        };
+
+        let mut ctx = policy::RequestContext::new(req.query().clone(), node_uuid, now);
+        policy::check_basearch(&ctx.basearch).map_err(|e| crate::invalid_param("basearch", &e))?;
+        ctx.stream_salt = self.rollout_salts.get(&ctx.stream).cloned();
+        Ok(ctx)
+    }
+
+    /// Check whether `req` carries the configured admin token.
+    fn is_admin(&self, req: &HttpRequest<AppState>) -> bool {
+        let expected = match &self.admin_token {
+            Some(token) => token.0.as_bytes(),
+            None => return false,
+        };
+        let provided = match req.headers().get(ADMIN_TOKEN_HEADER) {
+            Some(value) => value.as_bytes(),
+            None => return false,
+        };
+        // Compare in constant time (for a given length).
+        provided.len() == expected.len()
+            && provided
+                .iter()
+                .zip(expected)
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+pub(crate) fn pe_serve_graph(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let node_uuid = match parse_node_uuid(&req.query()) {
+        Ok(id) => id,
+        Err(e) => return Box::new(future::ok(crate::invalid_param("node_uuid", &e))),
+    };
+    pe_record_metrics(&req, node_uuid.as_ref());
+
+    let ctx = match req.state().request_context(&req, node_uuid) {
+        Ok(ctx) => ctx,
+        Err(resp) => return Box::new(future::ok(resp)),
+    };
+    let format = match negotiate::GraphFormat::negotiate(&req) {
+        Ok(format) => format,
+        Err(resp) => return Box::new(future::ok(resp)),
+    };
+    ROLLOUT_WARINESS.observe(ctx.wariness(None));
+    let policies = Arc::clone(&req.state().policies);
+
+    let cached_graph = req
+        .state()
+        .graph_client
+        .send(GetGraph {
+            stream: ctx.stream.clone(),
+            basearch: ctx.basearch.clone(),
+        })
+        .flatten();
+
+    let resp = cached_graph.and_then(move |remote| {
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Ok(crate::unknown_stream(&ctx.stream)),
+        };
+        let age = remote.age();
+        if let Some(resp) = check_client_version(&ctx, &remote.graph) {
+            return Ok(resp);
+        }
+        let mut explain = policy::Explanation::disabled();
+        let graph = policies.apply(remote.graph, &ctx, &mut explain)?;
+        let mut resp = HttpResponse::Ok();
+        if let Some(age) = age {
+            resp.header(health::GRAPH_AGE_HEADER, age.as_secs().to_string());
+        }
+        format.respond(resp, &graph)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve the recommended update path from a given release.
+///
+/// The path leads to the newest release the client would currently be
+/// offered, given its rollout wariness, going through all mandatory barriers.
+pub(crate) fn pe_serve_path(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    V1_PATH_INCOMING_REQS.inc();
+
+    let node_uuid = match parse_node_uuid(&req.query()) {
+        Ok(id) => id,
+        Err(e) => return Box::new(future::ok(crate::invalid_param("node_uuid", &e))),
+    };
+    let from = match req.query().get("from").filter(|v| !v.is_empty()) {
+        Some(version) => version.clone(),
+        None => {
+            return Box::new(future::ok(crate::invalid_param(
+                "from",
+                "missing release version",
+            )))
+        }
+    };
+
+    let ctx = match req.state().request_context(&req, node_uuid) {
+        Ok(ctx) => ctx,
+        Err(resp) => return Box::new(future::ok(resp)),
+    };
+    let policies = Arc::clone(&req.state().policies);
+
+    let cached_graph = req
+        .state()
+        .graph_client
+        .send(GetGraph {
+            stream: ctx.stream.clone(),
+            basearch: ctx.basearch.clone(),
+        })
+        .flatten();
+
+    let resp = cached_graph.and_then(move |remote| {
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Ok(crate::unknown_stream(&ctx.stream)),
+        };
+        let age = remote.age();
+        if let Some(resp) = check_client_version(&ctx, &remote.graph) {
+            return Ok(resp);
+        }
+        let mut explain = policy::Explanation::disabled();
+        let graph = policies.apply(remote.graph, &ctx, &mut explain)?;
+        let index = match graph.nodes.iter().position(|node| node.version == from) {
+            Some(index) => index,
+            None => return Ok(crate::unknown_version(&from)),
+        };
+        let path: Vec<_> = graph
+            .update_path(index)
+            .into_iter()
+            .map(|index| &graph.nodes[index])
+            .collect();
+        let body = serde_json::json!({
+            "from": from,
+            "to": path.last().map(|node| &node.version),
+            "latest": graph.nodes.last().map(|node| &node.version),
+            "path": path,
+        });
+        let json = serde_json::to_string_pretty(&body)?;
+        let mut resp = HttpResponse::Ok();
+        resp.content_type("application/json");
+        if let Some(age) = age {
+            resp.header(health::GRAPH_AGE_HEADER, age.as_secs().to_string());
+        }
+        Ok(resp.body(json))
+    });
+
+    Box::new(resp)
+}
+
+/// Serve an explanation of policy decisions for a client.
+///
+/// This fetches the graph from the graph-builder along with the decisions of
+/// its own policies, and applies the policy-engine chain on top, reporting
+/// which releases and update edges were dropped by each policy.
+pub(crate) fn pe_serve_explain(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let node_uuid = match parse_node_uuid(&req.query()) {
+        Ok(id) => id,
+        Err(e) => return Box::new(future::ok(crate::invalid_param("node_uuid", &e))),
+    };
+
+    let ctx = match req.state().request_context(&req, node_uuid) {
+        Ok(ctx) => ctx,
+        Err(resp) => return Box::new(future::ok(resp)),
+    };
+    let policies = Arc::clone(&req.state().policies);
+
+    let explained_graph = req
+        .state()
+        .graph_client
+        .send(GetExplainedGraph {
+            stream: ctx.stream.clone(),
+            params: ctx.params.clone(),
+        })
+        .flatten();
+
+    let resp = explained_graph.and_then(move |explained| {
+        let explained = match explained {
+            Some(explained) => explained,
+            None => return Ok(crate::unknown_stream(&ctx.stream)),
+        };
+        let mut explain = policy::Explanation::recording();
+        explain.extend(explained.explanation);
+        let graph = explained.graph;
+        if let Some(resp) = check_client_version(&ctx, &graph) {
+            return Ok(resp);
+        }
+        let graph = policies.apply(graph, &ctx, &mut explain)?;
+        let body = serde_json::json!({
+            "wariness": ctx.wariness(None),
+            "dropped_nodes": explain.dropped_nodes,
+            "dropped_edges": explain.dropped_edges,
+            "graph": graph,
+        });
+        let json = serde_json::to_string_pretty(&body)?;
+        let resp = HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json);
+        Ok(resp)
+    });
+
+    Box::new(resp)
+}
+
+/// Serve readiness probes, failing while the graph-builder is not ready.
+pub(crate) fn pe_serve_ready(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let resp = req
+        .state()
+        .graph_client
+        .send(CheckReady {})
+        .flatten()
+        .map(health::readiness);
+    Box::new(resp)
+}
+
+/// Reject clients running a release which is not in `graph`, if any.
+fn check_client_version(
+    ctx: &policy::RequestContext,
+    graph: &graph::Graph,
+) -> Option<HttpResponse> {
+    let client = ctx.client_version()?;
+    if client.find_in(graph).is_some() {
+        return None;
+    }
+    UNKNOWN_VERSIONS.with_label_values(&[&ctx.stream]).inc();
+    Some(crate::unknown_version(&client.to_string()))
+}
+
+/// Parse the `node_uuid` client parameter, if present.
+///
+/// Both the hyphenated and the simple (32 hex digits) forms are accepted.
+fn parse_node_uuid(params: &HashMap<String, String>) -> Result<Option<Uuid>, String> {
+    let input = match params.get("node_uuid") {
+        Some(input) if !input.is_empty() => input,
+        _ => return Ok(None),
+    };
+    let id = Uuid::parse_str(input)
+        .map_err(|e| format!("'{}' is not a valid 128-bit ID: {}", input, e))?;
+    Ok(Some(id))
+}
+
+pub(crate) fn pe_record_metrics(req: &HttpRequest<AppState>, node_uuid: Option<&Uuid>) {
+    V1_GRAPH_INCOMING_REQS.inc();
+
+    let population = &req.state().population;
+    if let Some(id) = node_uuid {
+        let client_uuid = policy::node_digest(id, "");
+        if !population.maybe_contains(client_uuid) {
+            population.insert(client_uuid);
+            UNIQUE_IDS.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{compute_wariness, node_digest};
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn uuid(input: &str) -> Uuid {
+        parse_node_uuid(&params(&[("node_uuid", input)]))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn node_uuid_validation() {
+        assert_eq!(parse_node_uuid(&params(&[])), Ok(None));
+        assert_eq!(parse_node_uuid(&params(&[("node_uuid", "")])), Ok(None));
+
+        let hyphenated = uuid("de4dbe6e-0b86-4e8a-8a4c-3e5a1bd0a35e");
+        let simple = uuid("DE4DBE6E0B864E8A8A4C3E5A1BD0A35E");
+        assert_eq!(hyphenated, simple);
+
+        for input in &[
+            "foo",
+            "de4dbe6e0b864e8a8a4c3e5a1bd0a35",
+            "de4dbe6e0b864e8a8a4c3e5a1bd0a35e0",
+            "ze4dbe6e0b864e8a8a4c3e5a1bd0a35e",
+        ] {
+            let res = parse_node_uuid(&params(&[("node_uuid", input)]));
+            assert!(res.is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn node_digest_golden() {
+        // These values must never change, as they determine rollout ordering.
+        let cases = [
+            ("00000000000000000000000000000000", 0x3747_08ff_f771_9dd5),
+            ("de4dbe6e0b864e8a8a4c3e5a1bd0a35e", 0xb473_2461_cf5e_0ca7),
+            (
+                "ffffffff-ffff-ffff-ffff-ffffffffffff",
+                0x5ac6_a594_5f16_5009,
+            ),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(node_digest(&uuid(input), ""), *expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn wariness_golden() {
+        let cases = [
+            ("00000000000000000000000000000000", 0.21592766045745868),
+            ("de4dbe6e0b864e8a8a4c3e5a1bd0a35e", 0.7048819292825714),
+            ("ffffffff-ffff-ffff-ffff-ffffffffffff", 0.35459360954617947),
+        ];
+        for (input, expected) in &cases {
+            let id = uuid(input);
+            let wariness = compute_wariness(&params(&[]), Some(&id), None);
+            assert!(
+                (wariness - expected).abs() < 1e-12,
+                "{}: {}",
+                input,
+                wariness
+            );
+        }
+    }
+
+    #[test]
+    fn wariness_overrides() {
+        let id = uuid("de4dbe6e0b864e8a8a4c3e5a1bd0a35e");
+        let explicit = params(&[("rollout_wariness", "0.25")]);
+        assert_eq!(compute_wariness(&explicit, Some(&id), Some("salt")), 0.25);
+        let clamped = params(&[("rollout_wariness", "7")]);
+        assert_eq!(compute_wariness(&clamped, None, None), 1.0);
+    }
+
+    #[test]
+    fn wariness_without_uuid_golden() {
+        // Clients without a UUID share the wariness of an empty ID.
+        let cases = [
+            (None, 0.8894159948913374),
+            (Some("2019-09"), 0.4879428022691238),
+        ];
+        for (salt, expected) in &cases {
+            let wariness = compute_wariness(&params(&[]), None, *salt);
+            assert!(
+                (wariness - expected).abs() < 1e-12,
+                "{:?}: {}",
+                salt,
+                wariness
+            );
+        }
+    }
+
+    #[test]
+    fn wariness_salted_golden() {
+        let id = uuid("de4dbe6e0b864e8a8a4c3e5a1bd0a35e");
+        let cases = [
+            (None, 0.7048819292825714),
+            (Some(""), 0.7048819292825714),
+            (Some("2019-09"), 0.5290111112836536),
+            (Some("stable"), 0.26577385709845236),
+        ];
+        for (salt, expected) in &cases {
+            let wariness = compute_wariness(&params(&[]), Some(&id), *salt);
+            assert!(
+                (wariness - expected).abs() < 1e-12,
+                "{:?}: {}",
+                salt,
+                wariness
+            );
+        }
+    }
+}
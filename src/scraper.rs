@@ -1,97 +1,372 @@
-use crate::{graph, metadata};
+use crate::clock::Clock;
+use crate::diff::GraphDiff;
+use crate::snapshot::{self, PersistGraph, SnapshotWriter};
+use crate::upstream::{self, FailureKind, Fetched, UpstreamClient, UpstreamDoc};
+use crate::{config, graph, metadata, overrides, validate};
 use actix::prelude::*;
-use failure::{Error, Fallible};
-use futures::future;
+use chrono::{DateTime, Utc};
+use failure::{bail, format_err, Error, Fallible};
 use futures::prelude::*;
-use prometheus::{IntCounter, IntGauge};
-use reqwest::Method;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Label for the release-index upstream.
+static RELEASES_UPSTREAM: &str = "releases";
+/// Label for the updates metadata upstream.
+static UPDATES_UPSTREAM: &str = "updates";
 
 lazy_static::lazy_static! {
-    static ref GRAPH_FINAL_EDGES: IntGauge = register_int_gauge!(opts!(
+    static ref GRAPH_FINAL_EDGES: IntGaugeVec = register_int_gauge_vec!(
         "dumnati_gb_scraper_graph_final_edges",
-        "Number of edges in the cached graph, after processing"
-    )).unwrap();
-    static ref GRAPH_FINAL_RELEASES: IntGauge = register_int_gauge!(opts!(
+        "Number of edges in the cached graph, after processing",
+        &["stream"]
+    ).unwrap();
+    static ref GRAPH_FINAL_RELEASES: IntGaugeVec = register_int_gauge_vec!(
         "dumnati_gb_scraper_graph_final_releases",
-        "Number of releases in the cached graph, after processing"
-    )).unwrap();
-    static ref LAST_REFRESH: IntGauge = register_int_gauge!(opts!(
+        "Number of releases in the cached graph, after processing",
+        &["stream"]
+    ).unwrap();
+    static ref LAST_REFRESH: IntGaugeVec = register_int_gauge_vec!(
         "dumnati_gb_scraper_graph_last_refresh_timestamp",
-        "UTC timestamp of last graph refresh"
-    )).unwrap();
-    static ref UPSTREAM_SCRAPES: IntCounter = register_int_counter!(opts!(
+        "UTC timestamp of last graph refresh",
+        &["stream"]
+    ).unwrap();
+    static ref GRAPH_REFUSED: IntGaugeVec = register_int_gauge_vec!(
+        "dumnati_gb_scraper_graph_refused",
+        "Whether the graph assembled from latest upstream metadata was refused",
+        &["stream"]
+    ).unwrap();
+    static ref GRAPH_FROM_SNAPSHOT: IntGaugeVec = register_int_gauge_vec!(
+        "dumnati_gb_scraper_graph_from_snapshot",
+        "Whether the cached graph was loaded from an on-disk snapshot",
+        &["stream"]
+    ).unwrap();
+    static ref CHANGED_SCRAPES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_upstream_changed_total",
+        "Total number of upstream scrapes with changed metadata",
+        &["stream"]
+    )
+    .unwrap();
+    static ref UNCHANGED_SCRAPES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_upstream_unchanged_total",
+        "Total number of upstream scrapes with unchanged metadata",
+        &["stream"]
+    )
+    .unwrap();
+    static ref VALIDATION_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_graph_validation_failures_total",
+        "Total number of assembled graphs rejected by validation",
+        &["stream"]
+    )
+    .unwrap();
+    static ref REJECTED_REGRESSIONS: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_graph_regressions_total",
+        "Total number of refreshed graphs rejected as suspicious regressions",
+        &["stream", "check"]
+    )
+    .unwrap();
+    static ref UPSTREAM_SCRAPES: IntCounterVec = register_int_counter_vec!(
         "dumnati_gb_scraper_upstream_scrapes_total",
-        "Total number of upstream scrapes"
-    ))
+        "Total number of upstream scrapes",
+        &["stream"]
+    )
     .unwrap();
 }
 
-/// Release scraper.
-#[derive(Clone, Debug)]
+/// Release scraper, for a single stream.
+#[derive(Debug)]
 pub struct Scraper {
+    stream: String,
     graph: graph::Graph,
-    hclient: reqwest::r#async::Client,
+    source: GraphSource,
+    refreshed: Option<DateTime<Utc>>,
+    refused: bool,
+    client: UpstreamClient,
     stream_metadata_url: reqwest::Url,
     release_index_url: reqwest::Url,
+    refresh_interval: std::time::Duration,
+    state_dir: Option<std::path::PathBuf>,
+    /// Snapshot writer, started along with this actor if `state_dir` is set.
+    snapshot_writer: Option<Addr<SnapshotWriter>>,
+    /// Watcher of local sources, living as long as this actor.
+    watcher: Option<upstream::FileWatcher>,
+    rollout_overrides_path: Option<std::path::PathBuf>,
+    rollout_overrides: HashMap<String, String>,
+    regression_checks: config::RegressionChecks,
+    basearches: Vec<String>,
+    diffs: VecDeque<GraphDiff>,
+    diff_history: usize,
+    releases_doc: Option<UpstreamDoc>,
+    updates_doc: Option<UpstreamDoc>,
+    pending_tick: Option<SpawnHandle>,
+    sources_changed: bool,
+    clock: Arc<dyn Clock>,
+}
+
+/// Origin of the cached graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum GraphSource {
+    /// No graph available yet.
+    Empty,
+    /// Graph loaded from an on-disk snapshot.
+    Snapshot,
+    /// Graph assembled from upstream metadata.
+    Upstream,
+}
+
+impl GraphSource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            GraphSource::Empty => "empty",
+            GraphSource::Snapshot => "snapshot",
+            GraphSource::Upstream => "upstream",
+        }
+    }
 }
 
 impl Scraper {
-    pub fn new<S>(stream: S) -> Fallible<Self>
-    where
-        S: Into<String>,
-    {
-        let vars = hashmap! { "stream".to_string() => stream.into() };
-        let releases_json = envsubst::substitute(metadata::RELEASES_JSON, &vars)?;
-        let stream_json = envsubst::substitute(metadata::STREAM_JSON, &vars)?;
-        let scraper = Self {
+    pub(crate) fn new(
+        stream: &str,
+        cfg: &config::UpstreamSettings,
+        clock: Arc<dyn Clock>,
+    ) -> Fallible<Self> {
+        let mut scraper = Self {
+            stream: stream.to_string(),
             graph: graph::Graph::default(),
-            hclient: reqwest::r#async::ClientBuilder::new().build()?,
-            release_index_url: reqwest::Url::parse(&releases_json)?,
-            stream_metadata_url: reqwest::Url::parse(&stream_json)?,
+            source: GraphSource::Empty,
+            refreshed: None,
+            refused: false,
+            client: UpstreamClient::new(stream, cfg)?,
+            release_index_url: config::UpstreamSettings::render_url(&cfg.releases_url, stream)?,
+            stream_metadata_url: config::UpstreamSettings::render_url(&cfg.updates_url, stream)?,
+            refresh_interval: cfg.refresh_interval,
+            state_dir: cfg.state_dir.clone(),
+            snapshot_writer: None,
+            watcher: None,
+            rollout_overrides_path: cfg.rollout_overrides.clone(),
+            rollout_overrides: HashMap::new(),
+            regression_checks: cfg.regression_checks.clone(),
+            basearches: cfg.basearches.clone(),
+            diffs: VecDeque::with_capacity(cfg.graph_diff_history),
+            diff_history: cfg.graph_diff_history,
+            releases_doc: None,
+            updates_doc: None,
+            pending_tick: None,
+            sources_changed: false,
+            clock,
         };
+        scraper.watch_local_sources()?;
+        scraper.load_snapshot();
         Ok(scraper)
     }
 
-    /// Return a request builder with base URL and parameters set.
-    fn new_request(
-        &self,
-        method: reqwest::Method,
-        url: reqwest::Url,
-    ) -> Fallible<reqwest::r#async::RequestBuilder> {
-        let builder = self.hclient.request(method, url);
-        Ok(builder)
+    /// Watch `file://` upstream sources and rollout overrides, if any.
+    ///
+    /// Changes are only acted upon once the actor is started.
+    fn watch_local_sources(&mut self) -> Fallible<()> {
+        let mut paths: Vec<_> = [&self.release_index_url, &self.stream_metadata_url]
+            .iter()
+            .filter(|url| url.scheme() == "file")
+            .filter_map(|url| url.to_file_path().ok())
+            .collect();
+        paths.extend(self.rollout_overrides_path.clone());
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        let watcher = upstream::FileWatcher::new(paths)
+            .map_err(|e| format_err!("stream '{}': {}", self.stream, e))?;
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+
+    /// Try to start from the last persisted graph, if any.
+    fn load_snapshot(&mut self) {
+        let state_dir = match &self.state_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        match snapshot::load_graph(state_dir, &self.stream) {
+            Ok(Some((graph, timestamp))) => {
+                log::info!(
+                    "stream '{}': loaded snapshot with {} releases",
+                    self.stream,
+                    graph.nodes.len()
+                );
+                self.graph = graph;
+                self.source = GraphSource::Snapshot;
+                self.refreshed = Some(timestamp);
+            }
+            Ok(None) => log::debug!("stream '{}': no snapshot found", self.stream),
+            Err(e) => log::warn!("stream '{}': ignoring snapshot: {}", self.stream, e),
+        }
+
+        let from_snapshot = self.source == GraphSource::Snapshot;
+        GRAPH_FROM_SNAPSHOT
+            .with_label_values(&[&self.stream])
+            .set(from_snapshot as i64);
     }
 
     /// Fetch releases from release-index.
-    fn fetch_releases(&self) -> impl Future<Item = Vec<metadata::Release>, Error = Error> {
-        let url = self.release_index_url.clone();
-        let req = self.new_request(Method::GET, url);
-        future::result(req)
-            .and_then(|req| req.send().from_err())
-            .and_then(|resp| resp.error_for_status().map_err(Error::from))
-            .and_then(|mut resp| resp.json::<metadata::ReleasesJSON>().from_err())
-            .map(|json| json.releases)
+    fn fetch_releases(&self) -> impl Future<Item = Fetched, Error = Error> {
+        self.client.fetch(
+            RELEASES_UPSTREAM,
+            self.release_index_url.clone(),
+            self.releases_doc.as_ref(),
+        )
     }
 
     /// Fetch updates metadata.
-    fn fetch_updates(&self) -> impl Future<Item = metadata::UpdatesJSON, Error = Error> {
-        let url = self.stream_metadata_url.clone();
-        let req = self.new_request(Method::GET, url);
-        future::result(req)
-            .and_then(|req| req.send().from_err())
-            .and_then(|resp| resp.error_for_status().map_err(Error::from))
-            .and_then(|mut resp| resp.json::<metadata::UpdatesJSON>().from_err())
+    fn fetch_updates(&self) -> impl Future<Item = Fetched, Error = Error> {
+        self.client.fetch(
+            UPDATES_UPSTREAM,
+            self.stream_metadata_url.clone(),
+            self.updates_doc.as_ref(),
+        )
     }
 
-    /// Combine release-index and updates metadata.
-    fn assemble_graph(&self) -> impl Future<Item = graph::Graph, Error = Error> {
-        let stream_updates = self.fetch_updates();
-        let stream_releases = self.fetch_releases();
+    /// Combine release-index and updates metadata, see `assemble_graph`.
+    fn assemble_upstream_graph(
+        &self,
+        releases_json: &[u8],
+        updates_json: &[u8],
+        rollout_overrides: &HashMap<String, String>,
+    ) -> Fallible<graph::Graph> {
+        let decode_failure = |upstream, e| {
+            upstream::record_failure(&self.stream, upstream, FailureKind::JsonDecode);
+            format_err!("failed to decode {}: {}", upstream, e)
+        };
+        let releases = serde_json::from_slice::<metadata::ReleasesJSON>(releases_json)
+            .map_err(|e| decode_failure(RELEASES_UPSTREAM, e))?;
+        let updates = serde_json::from_slice::<metadata::UpdatesJSON>(updates_json)
+            .map_err(|e| decode_failure(UPDATES_UPSTREAM, e))?;
+        assemble_graph(
+            &self.stream,
+            releases,
+            updates,
+            &self.basearches,
+            rollout_overrides,
+            &self.graph,
+            self.clock.now().timestamp(),
+        )
+    }
 
-        let updates = stream_releases
-            .join(stream_updates)
-            .and_then(|(graph, updates)| graph::Graph::from_metadata(graph, updates));
-        updates
+    /// Refuse refreshed graphs which look like suspicious regressions of the cached one.
+    fn check_regression(&self, next: &graph::Graph) -> Fallible<()> {
+        let report = validate::check_regression(&self.graph, next, &self.regression_checks);
+        if !report.has_errors() {
+            return Ok(());
+        }
+        for finding in report.errors() {
+            log::error!("stream '{}': {}", self.stream, finding);
+            REJECTED_REGRESSIONS
+                .with_label_values(&[&self.stream, finding.kind])
+                .inc();
+        }
+        bail!("refreshed graph looks like a regression, keeping previous one");
+    }
+
+    /// Log changes to the cached graph, and keep them for auditing.
+    fn record_diff(&mut self, diff: GraphDiff) {
+        if diff.is_empty() {
+            return;
+        }
+        log::info!("stream '{}': graph changed, {}", self.stream, diff);
+        for release in &diff.added_releases {
+            log::debug!("stream '{}': added release '{}'", self.stream, release);
+        }
+        for release in &diff.removed_releases {
+            log::debug!("stream '{}': removed release '{}'", self.stream, release);
+        }
+        for edge in &diff.added_edges {
+            log::debug!("stream '{}': added edge {}", self.stream, edge);
+        }
+        for edge in &diff.removed_edges {
+            log::debug!("stream '{}': removed edge {}", self.stream, edge);
+        }
+        for change in &diff.changed_metadata {
+            log::debug!("stream '{}': {}", self.stream, change);
+        }
+
+        if self.diff_history == 0 {
+            return;
+        }
+        while self.diffs.len() >= self.diff_history {
+            self.diffs.pop_front();
+        }
+        self.diffs.push_back(diff);
+    }
+
+    /// Refresh the cached graph from fetched metadata, if anything changed.
+    ///
+    /// The graph is only considered refreshed if it was swapped or unchanged;
+    /// refused graphs are tracked separately.
+    fn refresh_graph(&mut self, releases: Fetched, updates: Fetched) -> Fallible<()> {
+        let releases = releases.or_cached(&self.releases_doc)?;
+        let updates = updates.or_cached(&self.updates_doc)?;
+
+        let result = self.update_graph(releases, updates);
+        self.refused = result.is_err();
+        GRAPH_REFUSED
+            .with_label_values(&[&self.stream])
+            .set(self.refused as i64);
+        result
+    }
+
+    /// Swap the cached graph with the one assembled from `releases` and `updates`,
+    /// unless refused.
+    fn update_graph(&mut self, releases: UpstreamDoc, updates: UpstreamDoc) -> Fallible<()> {
+        let stream = self.stream.clone();
+        let labels = [stream.as_str()];
+
+        let rollout_overrides = match &self.rollout_overrides_path {
+            Some(path) => overrides::read_rollout_overrides(path, &self.stream)?,
+            None => HashMap::new(),
+        };
+        let unchanged = self.source == GraphSource::Upstream
+            && Fetched::same_content(&releases, &self.releases_doc)
+            && Fetched::same_content(&updates, &self.updates_doc)
+            && rollout_overrides == self.rollout_overrides;
+
+        if unchanged {
+            UNCHANGED_SCRAPES.with_label_values(&labels).inc();
+        } else {
+            CHANGED_SCRAPES.with_label_values(&labels).inc();
+            let graph =
+                self.assemble_upstream_graph(&releases.body, &updates.body, &rollout_overrides)?;
+            self.check_regression(&graph)?;
+            if let Some(writer) = &self.snapshot_writer {
+                writer.do_send(PersistGraph {
+                    stream: self.stream.clone(),
+                    graph: graph.clone(),
+                });
+            }
+            if self.source != GraphSource::Empty {
+                let diff = GraphDiff::compute(&self.graph, &graph, self.clock.now());
+                self.record_diff(diff);
+            }
+            self.graph = graph;
+            self.source = GraphSource::Upstream;
+            self.releases_doc = Some(releases);
+            self.updates_doc = Some(updates);
+            self.rollout_overrides = rollout_overrides;
+            GRAPH_FINAL_EDGES
+                .with_label_values(&labels)
+                .set(self.graph.edges.len() as i64);
+            GRAPH_FINAL_RELEASES
+                .with_label_values(&labels)
+                .set(self.graph.nodes.len() as i64);
+            GRAPH_FROM_SNAPSHOT.with_label_values(&labels).set(0);
+        }
+
+        let refresh_timestamp = self.clock.now();
+        self.refreshed = Some(refresh_timestamp);
+        LAST_REFRESH
+            .with_label_values(&labels)
+            .set(refresh_timestamp.timestamp());
+        Ok(())
     }
 }
 
@@ -99,6 +374,18 @@ impl Actor for Scraper {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.snapshot_writer = self.state_dir.clone().map(SnapshotWriter::start);
+        if let Some(watcher) = &mut self.watcher {
+            let addr = ctx.address();
+            if let Err(e) = watcher.on_change(move || addr.do_send(SourcesChanged {})) {
+                log::error!(
+                    "stream '{}': not watching local sources: {}",
+                    self.stream,
+                    e
+                );
+            }
+        }
+
         // Kick-start the state machine.
         Self::tick_now(ctx);
     }
@@ -114,21 +401,27 @@ impl Handler<RefreshTick> for Scraper {
     type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, _msg: RefreshTick, _ctx: &mut Self::Context) -> Self::Result {
-        UPSTREAM_SCRAPES.inc();
-
-        let updates = self.assemble_graph();
-
-        let update_graph = actix::fut::wrap_future::<_, Self>(updates)
-            .map_err(|err, _actor, _ctx| log::error!("{}", err))
-            .map(|graph, actor, _ctx| {
-                actor.graph = graph;
-                let refresh_timestamp = chrono::Utc::now();
-                LAST_REFRESH.set(refresh_timestamp.timestamp());
-                GRAPH_FINAL_EDGES.set(actor.graph.edges.len() as i64);
-                GRAPH_FINAL_RELEASES.set(actor.graph.nodes.len() as i64);
+        self.pending_tick = None;
+        UPSTREAM_SCRAPES.with_label_values(&[&self.stream]).inc();
+
+        let fetched = self.fetch_releases().join(self.fetch_updates());
+
+        let update_graph = actix::fut::wrap_future::<_, Self>(fetched)
+            .and_then(|(releases, updates), actor, _ctx| {
+                actix::fut::result(actor.refresh_graph(releases, updates))
+            })
+            .map_err(|err, actor, _ctx| {
+                log::error!("failed to refresh stream '{}': {}", actor.stream, err)
             })
-            .then(|_r, _actor, ctx| {
-                Self::tick_later(ctx, std::time::Duration::from_secs(30));
+            .then(|_r, actor, ctx| {
+                // Refresh again right away if local sources changed meanwhile.
+                let delay = if actor.sources_changed {
+                    actor.sources_changed = false;
+                    std::time::Duration::from_secs(0)
+                } else {
+                    actor.refresh_interval
+                };
+                actor.pending_tick = Some(Self::tick_later(ctx, delay));
                 actix::fut::ok(())
             });
 
@@ -136,37 +429,177 @@ impl Handler<RefreshTick> for Scraper {
     }
 }
 
-pub(crate) struct GetCachedGraph {
-    pub(crate) stream: String,
+/// Notification that local upstream sources changed.
+pub(crate) struct SourcesChanged {}
+
+impl Message for SourcesChanged {
+    type Result = ();
 }
 
-impl Default for GetCachedGraph {
-    fn default() -> Self {
-        Self {
-            stream: "testing".to_string(),
+impl Handler<SourcesChanged> for Scraper {
+    type Result = ();
+
+    fn handle(&mut self, _msg: SourcesChanged, ctx: &mut Self::Context) -> Self::Result {
+        log::debug!("stream '{}': local sources changed", self.stream);
+        match self.pending_tick.take() {
+            // Idle, refresh right away.
+            Some(handle) => {
+                ctx.cancel_future(handle);
+                Self::tick_now(ctx);
+            }
+            // Refresh in progress, schedule another one after it.
+            None => self.sources_changed = true,
         }
     }
 }
 
+pub(crate) struct GetCachedGraph {
+    pub(crate) stream: String,
+}
+
+/// Cached graph, along with its origin.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedGraph {
+    pub(crate) graph: graph::Graph,
+    pub(crate) status: GraphStatus,
+}
+
 impl Message for GetCachedGraph {
-    type Result = Result<graph::Graph, Error>;
+    type Result = Result<CachedGraph, Error>;
 }
 
 impl Handler<GetCachedGraph> for Scraper {
-    type Result = ResponseActFuture<Self, graph::Graph, Error>;
+    type Result = ResponseActFuture<Self, CachedGraph, Error>;
     fn handle(&mut self, msg: GetCachedGraph, _ctx: &mut Self::Context) -> Self::Result {
-        use failure::format_err;
-        if msg.stream != "testing" {
+        if msg.stream != self.stream {
             return Box::new(actix::fut::err(format_err!(
                 "unexpected stream '{}'",
                 msg.stream
             )));
         }
-        Box::new(actix::fut::ok(self.graph.clone()))
+        let cached = CachedGraph {
+            graph: self.graph.clone(),
+            status: self.status(),
+        };
+        Box::new(actix::fut::ok(cached))
+    }
+}
+
+pub(crate) struct GetGraphDiffs {
+    pub(crate) stream: String,
+}
+
+impl Message for GetGraphDiffs {
+    type Result = Result<Vec<GraphDiff>, Error>;
+}
+
+impl Handler<GetGraphDiffs> for Scraper {
+    type Result = Result<Vec<GraphDiff>, Error>;
+    fn handle(&mut self, msg: GetGraphDiffs, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.stream != self.stream {
+            return Err(format_err!("unexpected stream '{}'", msg.stream));
+        }
+        Ok(self.diffs.iter().cloned().collect())
+    }
+}
+
+/// Assemble the graph of `stream` as served, from release-index and updates metadata.
+///
+/// Metadata is validated, with every release expected to have a payload for
+/// each of `basearches`. Local rollout overrides are then applied, and paused
+/// rollouts are pinned against the `previous` graph, as of `now`.
+pub(crate) fn assemble_graph(
+    stream: &str,
+    releases: metadata::ReleasesJSON,
+    updates: metadata::UpdatesJSON,
+    basearches: &[String],
+    rollout_overrides: &HashMap<String, String>,
+    previous: &graph::Graph,
+    now: i64,
+) -> Fallible<graph::Graph> {
+    let mut report = validate::check_metadata(&releases.releases, &updates, basearches);
+    let mut graph = graph::Graph::from_metadata(releases.releases, updates)?;
+    report.extend(validate::check_graph(&graph));
+    for finding in &report.findings {
+        log::warn!("stream '{}': {}", stream, finding);
+    }
+    if report.has_errors() {
+        VALIDATION_FAILURES.with_label_values(&[stream]).inc();
+        bail!("graph failed validation");
+    }
+
+    graph.apply_rollout_overrides(rollout_overrides);
+    graph.pin_paused_rollouts(previous, now);
+    Ok(graph)
+}
+
+/// Freshness status of the cached graph.
+#[derive(Clone, Debug)]
+pub(crate) struct GraphStatus {
+    pub(crate) stream: String,
+    pub(crate) source: GraphSource,
+    pub(crate) refreshed: Option<DateTime<Utc>>,
+    /// When this status was taken.
+    pub(crate) checked: DateTime<Utc>,
+}
+
+impl GraphStatus {
+    /// Return the age of the cached graph, if any.
+    pub(crate) fn age(&self) -> Option<std::time::Duration> {
+        self.refreshed
+            .map(|ts| (self.checked - ts).to_std().unwrap_or_default())
+    }
+}
+
+/// Return why graphs cannot be served, if so, given the status of all streams.
+///
+/// Every stream needs a graph loaded, and refreshed within `max_age` (if set).
+/// A graph kept in service because refreshes are refused or failing thus
+/// eventually goes stale too.
+pub(crate) fn readiness_problems(
+    statuses: &[GraphStatus],
+    max_age: Option<std::time::Duration>,
+) -> Vec<String> {
+    statuses
+        .iter()
+        .filter_map(|status| match (status.source, status.age(), max_age) {
+            (GraphSource::Empty, _, _) => {
+                Some(format!("stream '{}': no graph loaded", status.stream))
+            }
+            (_, Some(age), Some(max_age)) if age > max_age => Some(format!(
+                "stream '{}': graph is stale ({}s old)",
+                status.stream,
+                age.as_secs()
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) struct GetGraphStatus {}
+
+impl Message for GetGraphStatus {
+    type Result = GraphStatus;
+}
+
+impl Handler<GetGraphStatus> for Scraper {
+    type Result = MessageResult<GetGraphStatus>;
+    fn handle(&mut self, _msg: GetGraphStatus, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.status())
     }
 }
 
 impl Scraper {
+    /// Return the freshness status of the cached graph.
+    fn status(&self) -> GraphStatus {
+        GraphStatus {
+            stream: self.stream.clone(),
+            source: self.source,
+            refreshed: self.refreshed,
+            checked: self.clock.now(),
+        }
+    }
+
     /// Schedule an immediate refresh the state machine.
     pub fn tick_now(ctx: &mut Context<Self>) {
         ctx.notify(RefreshTick {})
@@ -177,3 +610,206 @@ impl Scraper {
         ctx.notify_later(RefreshTick {}, after)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::upstream::UpstreamDoc;
+    use chrono::TimeZone;
+
+    /// Upstream settings for tests, independent from the environment.
+    fn upstream_settings() -> config::UpstreamSettings {
+        config::UpstreamSettings {
+            streams: vec!["testing".to_string()],
+            basearches: vec![],
+            releases_url: metadata::RELEASES_JSON.to_string(),
+            updates_url: metadata::STREAM_JSON.to_string(),
+            refresh_interval: std::time::Duration::from_secs(30),
+            request_timeout: std::time::Duration::from_secs(10),
+            max_retries: 0,
+            retry_initial_delay: std::time::Duration::from_millis(500),
+            retry_max_delay: std::time::Duration::from_millis(500),
+            state_dir: None,
+            rollout_overrides: None,
+            regression_checks: config::RegressionChecks {
+                max_release_drop_percent: 20,
+                allow_latest_release_removal: false,
+                allow_barrier_changes: false,
+            },
+            graph_diff_history: 20,
+        }
+    }
+
+    fn doc(value: serde_json::Value) -> Fetched {
+        Fetched::Changed(UpstreamDoc {
+            body: value.to_string().into_bytes(),
+            etag: None,
+            last_modified: None,
+        })
+    }
+
+    #[test]
+    fn per_stream_sources() {
+        let mut settings = upstream_settings();
+        settings.streams = vec!["stable".to_string(), "next".to_string()];
+        let clock = Arc::new(FixedClock(Utc.timestamp(1_570_000_000, 0)));
+        let stable = Scraper::new("stable", &settings, clock.clone()).unwrap();
+        let next = Scraper::new("next", &settings, clock).unwrap();
+
+        assert_eq!(
+            stable.release_index_url.as_str(),
+            "https://builds.coreos.fedoraproject.org/prod/streams/stable/releases.json"
+        );
+        assert_eq!(
+            stable.stream_metadata_url.as_str(),
+            "https://builds.coreos.fedoraproject.org/updates/stable.json"
+        );
+        assert_eq!(
+            next.release_index_url.as_str(),
+            "https://builds.coreos.fedoraproject.org/prod/streams/next/releases.json"
+        );
+        assert_eq!(
+            next.stream_metadata_url.as_str(),
+            "https://builds.coreos.fedoraproject.org/updates/next.json"
+        );
+        assert_eq!(stable.status().stream, "stable");
+        assert_eq!(next.status().stream, "next");
+        assert_eq!(next.status().source, GraphSource::Empty);
+    }
+
+    #[test]
+    fn refresh_with_fixed_clock() {
+        let now = Utc.timestamp(1_570_000_000, 0);
+        let clock = Arc::new(FixedClock(now));
+        let mut scraper = Scraper::new("testing", &upstream_settings(), clock).unwrap();
+
+        let releases = doc(serde_json::json!({ "releases": [
+            { "version": "30.1", "metadata": "", "commits": [] },
+        ] }));
+        let updates = doc(serde_json::json!({ "stream": "testing", "releases": [
+            { "version": "30.1", "metadata": { "rollout": { "state": "paused" } } },
+        ] }));
+        scraper.refresh_graph(releases, updates).unwrap();
+
+        let status = scraper.status();
+        assert_eq!(status.source, GraphSource::Upstream);
+        assert_eq!(status.refreshed, Some(now));
+        assert_eq!(status.age(), Some(std::time::Duration::from_secs(0)));
+        assert_eq!(
+            scraper.graph.nodes[0].metadata[metadata::ROLLOUT_PAUSED],
+            "1570000000"
+        );
+        assert!(scraper.diffs.is_empty());
+    }
+
+    #[test]
+    fn refuse_regression() {
+        let clock = Arc::new(FixedClock(Utc.timestamp(1_570_000_000, 0)));
+        let mut scraper = Scraper::new("regression", &upstream_settings(), clock).unwrap();
+        let updates = || doc(serde_json::json!({ "stream": "regression", "releases": [] }));
+
+        let releases = doc(serde_json::json!({ "releases": [
+            { "version": "30.1", "metadata": "", "commits": [] },
+            { "version": "30.2", "metadata": "", "commits": [] },
+        ] }));
+        scraper.refresh_graph(releases, updates()).unwrap();
+        assert_eq!(scraper.graph.nodes.len(), 2);
+        assert!(scraper.diffs.is_empty());
+
+        let rejected = || {
+            REJECTED_REGRESSIONS
+                .with_label_values(&["regression", "latest_release_removed"])
+                .get()
+        };
+        let rejected_before = rejected();
+        let truncated = doc(serde_json::json!({ "releases": [
+            { "version": "30.1", "metadata": "", "commits": [] },
+        ] }));
+        scraper.refresh_graph(truncated, updates()).unwrap_err();
+        assert_eq!(scraper.graph.nodes.len(), 2);
+        assert!(scraper.diffs.is_empty());
+        assert_eq!(rejected() - rejected_before, 1);
+    }
+
+    #[test]
+    fn readiness_across_streams() {
+        let now = Utc.timestamp(1_570_000_000, 0);
+        let status = |stream: &str, source, refreshed_secs_ago: Option<i64>| GraphStatus {
+            stream: stream.to_string(),
+            source,
+            refreshed: refreshed_secs_ago.map(|secs| now - chrono::Duration::seconds(secs)),
+            checked: now,
+        };
+        let max_age = Some(std::time::Duration::from_secs(600));
+
+        let fresh = status("stable", GraphSource::Upstream, Some(10));
+        let stale = status("testing", GraphSource::Upstream, Some(900));
+        let snapshot = status("next", GraphSource::Snapshot, Some(1200));
+        let empty = status("next", GraphSource::Empty, None);
+
+        assert!(readiness_problems(&[], max_age).is_empty());
+        assert!(readiness_problems(std::slice::from_ref(&fresh), max_age).is_empty());
+        assert_eq!(
+            readiness_problems(&[fresh.clone(), stale.clone()], max_age),
+            vec!["stream 'testing': graph is stale (900s old)"]
+        );
+        assert_eq!(
+            readiness_problems(&[stale.clone(), snapshot.clone()], max_age),
+            vec![
+                "stream 'testing': graph is stale (900s old)",
+                "stream 'next': graph is stale (1200s old)",
+            ]
+        );
+        assert!(readiness_problems(&[fresh.clone(), stale.clone(), snapshot], None).is_empty());
+        assert_eq!(
+            readiness_problems(&[fresh, empty], None),
+            vec!["stream 'next': no graph loaded"]
+        );
+    }
+
+    #[test]
+    fn unready_after_refused_refreshes() {
+        let start = Utc.timestamp(1_570_000_000, 0);
+        let max_age = Some(std::time::Duration::from_secs(600));
+        let mut scraper =
+            Scraper::new("refused", &upstream_settings(), Arc::new(FixedClock(start))).unwrap();
+        let problems = |scraper: &Scraper| readiness_problems(&[scraper.status()], max_age);
+        assert_eq!(
+            problems(&scraper),
+            vec!["stream 'refused': no graph loaded"]
+        );
+
+        let updates = || doc(serde_json::json!({ "releases": [] }));
+        let releases = || {
+            doc(serde_json::json!({ "releases": [
+                { "version": "30.1", "commits": [] },
+                { "version": "30.2", "commits": [] },
+            ] }))
+        };
+        scraper.refresh_graph(releases(), updates()).unwrap();
+        assert!(problems(&scraper).is_empty());
+
+        // Unchanged refreshes keep the graph fresh.
+        scraper.clock = Arc::new(FixedClock(start + chrono::Duration::seconds(500)));
+        scraper.refresh_graph(releases(), updates()).unwrap();
+        let later = start + chrono::Duration::seconds(1200);
+        scraper.clock = Arc::new(FixedClock(later));
+        assert_eq!(
+            problems(&scraper),
+            vec!["stream 'refused': graph is stale (700s old)"]
+        );
+
+        // Refused refreshes do not.
+        let truncated = doc(serde_json::json!({ "releases": [
+            { "version": "30.1", "commits": [] },
+        ] }));
+        scraper.refresh_graph(truncated, updates()).unwrap_err();
+        assert!(scraper.refused);
+        assert_eq!(scraper.graph.nodes.len(), 2);
+        assert_eq!(
+            problems(&scraper),
+            vec!["stream 'refused': graph is stale (700s old)"]
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Rollout simulation.
+//!
+//! This previews how rollouts progress over time, by evaluating the same
+//! wariness and throttling logic as the policy-engine against a synthetic
+//! population of nodes.
+
+use crate::clock::{self, Clock, SystemClock};
+use crate::graph::{CincinnatiPayload, Graph};
+use crate::{config, metadata, overrides, policy, scraper, upstream};
+use chrono::{DateTime, Duration, Utc};
+use failure::{bail, format_err, Fallible};
+use std::collections::HashMap;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+/// Default length of the simulated time range, in hours.
+static DEFAULT_RANGE_HOURS: i64 = 7 * 24;
+
+/// Options for the `simulate` subcommand.
+#[derive(Debug, StructOpt)]
+pub(crate) struct SimulateOptions {
+    #[structopt(flatten)]
+    pub sources: upstream::SourceOptions,
+    /// Start of the simulated time range, as a Unix timestamp or in RFC 3339 format (defaults to now).
+    #[structopt(long = "start")]
+    pub start: Option<String>,
+    /// End of the simulated time range, as a Unix timestamp or in RFC 3339 format (defaults to a week after start).
+    #[structopt(long = "end")]
+    pub end: Option<String>,
+    /// Interval between simulated points in time, in minutes.
+    #[structopt(long = "interval-minutes", default_value = "60")]
+    pub interval_minutes: u32,
+    /// Number of synthetic nodes.
+    #[structopt(long = "nodes", default_value = "10000")]
+    pub nodes: u32,
+    /// Seed for generating synthetic node UUIDs.
+    #[structopt(long = "seed", default_value = "0")]
+    pub seed: u64,
+}
+
+/// Share of nodes offered each rollout, at a point in time.
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    time: DateTime<Utc>,
+    shares: Vec<f64>,
+}
+
+/// Run a rollout simulation, printing results to stdout.
+pub(crate) fn run(settings: config::Settings, opts: SimulateOptions) -> Fallible<()> {
+    let start = match &opts.start {
+        Some(input) => clock::parse_time(input).map_err(|e| format_err!("invalid start: {}", e))?,
+        None => SystemClock::default().now(),
+    };
+    let end = match &opts.end {
+        Some(input) => clock::parse_time(input).map_err(|e| format_err!("invalid end: {}", e))?,
+        None => start + Duration::hours(DEFAULT_RANGE_HOURS),
+    };
+    if end < start {
+        bail!("end of time range is before its start");
+    }
+    if opts.interval_minutes == 0 {
+        bail!("interval must be non-zero");
+    }
+    if opts.nodes == 0 {
+        bail!("number of nodes must be non-zero");
+    }
+
+    let (releases, updates) = opts.sources.load(&settings.upstream)?;
+    let stream = &opts.sources.stream;
+    let rollout_overrides = match &settings.upstream.rollout_overrides {
+        Some(path) => overrides::read_rollout_overrides(path, stream)?,
+        None => HashMap::new(),
+    };
+    // Rollouts paused at an unknown time are frozen from the start.
+    let graph = scraper::assemble_graph(
+        stream,
+        releases,
+        updates,
+        &settings.upstream.basearches,
+        &rollout_overrides,
+        &Graph::default(),
+        start.timestamp(),
+    )?;
+
+    let rollouts: Vec<_> = graph
+        .nodes
+        .iter()
+        .filter(|node| node.metadata.contains_key(metadata::ROLLOUT))
+        .cloned()
+        .collect();
+    if rollouts.is_empty() {
+        println!("no rollouts in stream '{}'", stream);
+        return Ok(());
+    }
+
+    let population = synthetic_population(opts.nodes, opts.seed);
+    let salt = settings.policy_engine.rollout_salts.get(stream);
+    let interval = Duration::minutes(i64::from(opts.interval_minutes));
+    let mut times = vec![];
+    let mut time = start;
+    while time <= end {
+        times.push(time);
+        time = time + interval;
+    }
+    let samples = simulate(&rollouts, &population, salt.map(String::as_str), &times);
+
+    let widths: Vec<_> = rollouts.iter().map(|r| r.version.len().max(8)).collect();
+    print!("{:<25}", "time");
+    for (rollout, width) in rollouts.iter().zip(&widths) {
+        print!("  {:>width$}", rollout.version, width = width);
+    }
+    println!();
+    for sample in samples {
+        print!("{:<25}", sample.time.to_rfc3339());
+        for (share, width) in sample.shares.iter().zip(&widths) {
+            let percent = format!("{:.2}%", share * 100.0);
+            print!("  {:>width$}", percent, width = width);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Compute which share of `population` is offered each of the `rollouts`, at each of `times`.
+fn simulate(
+    rollouts: &[CincinnatiPayload],
+    population: &[Uuid],
+    stream_salt: Option<&str>,
+    times: &[DateTime<Utc>],
+) -> Vec<Sample> {
+    // Probe graph, with an update from a synthetic origin towards each rollout.
+    let mut probe = Graph::default();
+    probe.nodes.push(CincinnatiPayload {
+        version: String::new(),
+        metadata: HashMap::new(),
+        payload: String::new(),
+    });
+    for rollout in rollouts {
+        probe.edges.push((0, probe.nodes.len() as u64));
+        probe.nodes.push(rollout.clone());
+    }
+
+    let mut samples = Vec::with_capacity(times.len());
+    for time in times {
+        let mut offered = vec![0u32; rollouts.len()];
+        for id in population {
+            let mut ctx = policy::RequestContext::new(HashMap::new(), Some(*id), *time);
+            ctx.stream_salt = stream_salt.map(String::from);
+            let graph = policy::throttle_rollouts(
+                probe.clone(),
+                time.timestamp(),
+                |salt| ctx.wariness(salt),
+                &mut policy::Explanation::disabled(),
+            );
+            for (_, to) in graph.edges {
+                offered[to as usize - 1] += 1;
+            }
+        }
+        let shares = offered
+            .into_iter()
+            .map(|count| f64::from(count) / population.len() as f64)
+            .collect();
+        samples.push(Sample {
+            time: *time,
+            shares,
+        });
+    }
+    samples
+}
+
+/// Generate `size` random node UUIDs, deterministically from `seed`.
+fn synthetic_population(size: u32, seed: u64) -> Vec<Uuid> {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    (0..size)
+        .map(|_| {
+            uuid::Builder::from_bytes(rng.gen())
+                .set_variant(uuid::Variant::RFC4122)
+                .set_version(uuid::Version::Random)
+                .build()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn linear_rollout_simulation() {
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [
+                { "version": "30.1", "metadata": { "rollout": {
+                    "start_epoch": 1_570_000_000, "start_percentage": 0.0, "duration_minutes": 100,
+                } } },
+            ],
+        }))
+        .unwrap();
+        let releases = metadata::test_releases(&["30.1"]);
+        let graph = Graph::from_metadata(releases, updates).unwrap();
+
+        let population = synthetic_population(2000, 42);
+        assert_eq!(population, synthetic_population(2000, 42));
+        let times: Vec<_> = [-60, 0, 50 * 60, 100 * 60]
+            .iter()
+            .map(|offset| Utc.timestamp(1_570_000_000 + offset, 0))
+            .collect();
+        let samples = simulate(&graph.nodes, &population, None, &times);
+
+        let shares: Vec<_> = samples.iter().map(|s| s.shares[0]).collect();
+        assert_eq!(shares[0], 0.0);
+        assert_eq!(shares[1], 0.0);
+        assert!((shares[2] - 0.5).abs() < 0.05, "{}", shares[2]);
+        assert_eq!(shares[3], 1.0);
+    }
+
+    #[test]
+    fn paused_rollout_simulation() {
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "releases": [
+                { "version": "30.1", "metadata": { "rollout": {
+                    "start_epoch": 1_570_000_000, "start_percentage": 0.0, "duration_minutes": 100,
+                } } },
+            ],
+        }))
+        .unwrap();
+        let releases = metadata::ReleasesJSON {
+            releases: metadata::test_releases(&["30.1"]),
+        };
+        let overrides = hashmap! { "30.1".to_string() => "paused".to_string() };
+        let start = Utc.timestamp(1_570_000_000 + 50 * 60, 0);
+        let graph = scraper::assemble_graph(
+            "testing",
+            releases,
+            updates,
+            &[],
+            &overrides,
+            &Graph::default(),
+            start.timestamp(),
+        )
+        .unwrap();
+
+        let population = synthetic_population(2000, 42);
+        let times = [start, start + Duration::minutes(50)];
+        let samples = simulate(&graph.nodes, &population, None, &times);
+        assert_eq!(samples[0].shares, samples[1].shares);
+        assert!((samples[1].shares[0] - 0.5).abs() < 0.05);
+    }
+}
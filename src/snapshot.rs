@@ -0,0 +1,173 @@
+//! On-disk snapshots of the last good graph.
+//!
+//! Each stream gets its own directory under the configured state directory,
+//! holding the last assembled graph. Snapshots are written by a sync actor, so
+//! that scrapers never block on disk I/O.
+
+use crate::graph;
+use actix::prelude::*;
+use chrono::{DateTime, Utc};
+use failure::{format_err, Fallible};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Filename for the assembled graph.
+static GRAPH_FILE: &str = "graph.json";
+
+/// Atomically write `graph` to the state directory of `stream`.
+pub(crate) fn persist_graph(state_dir: &Path, stream: &str, graph: &graph::Graph) -> Fallible<()> {
+    let dir = stream_dir(state_dir, stream);
+    fs::create_dir_all(&dir)
+        .map_err(|e| format_err!("failed to create '{}': {}", dir.display(), e))?;
+
+    let graph_json = serde_json::to_vec(graph)?;
+    write_atomic(&dir, GRAPH_FILE, &graph_json)
+}
+
+/// Load the last persisted graph for `stream`, if any, along with its timestamp.
+pub(crate) fn load_graph(
+    state_dir: &Path,
+    stream: &str,
+) -> Fallible<Option<(graph::Graph, DateTime<Utc>)>> {
+    let path = stream_dir(state_dir, stream).join(GRAPH_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format_err!("failed to read '{}': {}", path.display(), e)),
+    };
+    let mtime = fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .map_err(|e| format_err!("failed to stat '{}': {}", path.display(), e))?;
+    let graph = serde_json::from_slice(&content)
+        .map_err(|e| format_err!("failed to parse '{}': {}", path.display(), e))?;
+    Ok(Some((graph, DateTime::<Utc>::from(mtime))))
+}
+
+/// Return the state directory for `stream`.
+fn stream_dir(state_dir: &Path, stream: &str) -> PathBuf {
+    state_dir.join(stream)
+}
+
+/// Write `content` to `dir/name`, via a temporary file and a rename.
+fn write_atomic(dir: &Path, name: &str, content: &[u8]) -> Fallible<()> {
+    let target = dir.join(name);
+    let tmp = dir.join(format!(".{}.tmp", name));
+
+    let write_tmp = || -> std::io::Result<()> {
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(content)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        // Make the rename itself durable.
+        fs::File::open(dir)?.sync_all()
+    };
+
+    write_tmp().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        format_err!("failed to write '{}': {}", target.display(), e)
+    })
+}
+
+/// Snapshot writer, running on its own thread.
+///
+/// Graphs are persisted in the order they are received.
+#[derive(Debug)]
+pub(crate) struct SnapshotWriter {
+    state_dir: PathBuf,
+}
+
+impl SnapshotWriter {
+    /// Start a writer for snapshots under `state_dir`.
+    pub(crate) fn start(state_dir: PathBuf) -> Addr<Self> {
+        SyncArbiter::start(1, move || Self {
+            state_dir: state_dir.clone(),
+        })
+    }
+}
+
+impl Actor for SnapshotWriter {
+    type Context = SyncContext<Self>;
+}
+
+/// Persist the graph of a stream, logging failures.
+pub(crate) struct PersistGraph {
+    pub(crate) stream: String,
+    pub(crate) graph: graph::Graph,
+}
+
+impl Message for PersistGraph {
+    type Result = ();
+}
+
+impl Handler<PersistGraph> for SnapshotWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: PersistGraph, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(e) = persist_graph(&self.state_dir, &msg.stream, &msg.graph) {
+            log::error!("stream '{}': failed to persist snapshot: {}", msg.stream, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn graph() -> graph::Graph {
+        let node = |version: &str| graph::CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: format!("sha{}", version),
+        };
+        graph::Graph {
+            nodes: vec![node("30.0"), node("30.1")],
+            edges: vec![(0, 1)],
+        }
+    }
+
+    #[test]
+    fn persist_and_reload() {
+        let state_dir =
+            std::env::temp_dir().join(format!("dumnati-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&state_dir);
+        assert!(load_graph(&state_dir, "testing").unwrap().is_none());
+
+        let graph = graph();
+        persist_graph(&state_dir, "testing", &graph).unwrap();
+        let (loaded, mtime) = load_graph(&state_dir, "testing").unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&graph).unwrap()
+        );
+        assert!(mtime <= Utc::now());
+        let dir = state_dir.join("testing");
+        assert!(!dir.join(".graph.json.tmp").exists());
+        assert!(load_graph(&state_dir, "stable").unwrap().is_none());
+
+        fs::write(dir.join(GRAPH_FILE), "not json").unwrap();
+        assert!(load_graph(&state_dir, "testing").is_err());
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn background_writer() {
+        let state_dir =
+            std::env::temp_dir().join(format!("dumnati-snapshot-writer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&state_dir);
+
+        let mut sys = System::new("dumnati-test");
+        let writer = SnapshotWriter::start(state_dir.clone());
+        let persist = writer.send(PersistGraph {
+            stream: "testing".to_string(),
+            graph: graph(),
+        });
+        sys.block_on(persist).unwrap();
+        let (loaded, _) = load_graph(&state_dir, "testing").unwrap().unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
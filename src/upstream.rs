@@ -0,0 +1,723 @@
+//! Upstream metadata fetching, over HTTP(S) with retries or from local files.
+
+use crate::{config, metadata};
+use failure::{bail, format_err, Error, Fallible};
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use prometheus::IntCounterVec;
+use reqwest::header;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+lazy_static::lazy_static! {
+    static ref UPSTREAM_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_upstream_failures_total",
+        "Total number of failed upstream requests, by upstream and failure kind",
+        &["stream", "upstream", "kind"]
+    )
+    .unwrap();
+    static ref UPSTREAM_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "dumnati_gb_scraper_upstream_retries_total",
+        "Total number of retried upstream requests",
+        &["stream", "upstream"]
+    )
+    .unwrap();
+}
+
+/// Upstream document, with validators for conditional requests.
+#[derive(Clone, Debug)]
+pub(crate) struct UpstreamDoc {
+    pub(crate) body: Vec<u8>,
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+}
+
+/// Result of an upstream fetch.
+#[derive(Debug)]
+pub(crate) enum Fetched {
+    /// Document not modified since the last fetch.
+    Unchanged,
+    /// New document content.
+    Changed(UpstreamDoc),
+}
+
+impl Fetched {
+    /// Resolve this fetch result to a document, using `cached` if unchanged.
+    pub(crate) fn or_cached(self, cached: &Option<UpstreamDoc>) -> Fallible<UpstreamDoc> {
+        match (self, cached) {
+            (Fetched::Changed(doc), _) => Ok(doc),
+            (Fetched::Unchanged, Some(doc)) => Ok(doc.clone()),
+            (Fetched::Unchanged, None) => {
+                Err(format_err!("not-modified response without cached content"))
+            }
+        }
+    }
+
+    /// Check whether `doc` has the same content as `cached`.
+    pub(crate) fn same_content(doc: &UpstreamDoc, cached: &Option<UpstreamDoc>) -> bool {
+        match cached {
+            Some(prev) => prev.body == doc.body,
+            None => false,
+        }
+    }
+}
+
+/// Kind of upstream failure, for metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FailureKind {
+    /// Transport-level failure (DNS, TCP, TLS, ...).
+    Connect,
+    /// Request did not complete in time.
+    Timeout,
+    /// Unexpected HTTP status code.
+    HttpStatus,
+    /// Malformed JSON document.
+    JsonDecode,
+    /// Failure reading a local file.
+    Io,
+}
+
+impl FailureKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            FailureKind::Connect => "connect",
+            FailureKind::Timeout => "timeout",
+            FailureKind::HttpStatus => "http_status",
+            FailureKind::JsonDecode => "json_decode",
+            FailureKind::Io => "io",
+        }
+    }
+}
+
+/// Record a failed upstream request.
+pub(crate) fn record_failure(stream: &str, upstream: &str, kind: FailureKind) {
+    UPSTREAM_FAILURES
+        .with_label_values(&[stream, upstream, kind.as_str()])
+        .inc();
+}
+
+/// Failure of a single request attempt.
+#[derive(Debug)]
+struct AttemptError {
+    kind: FailureKind,
+    retryable: bool,
+    error: Error,
+}
+
+impl AttemptError {
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        let kind = if err.is_timeout() {
+            FailureKind::Timeout
+        } else if err.status().is_some() {
+            FailureKind::HttpStatus
+        } else {
+            FailureKind::Connect
+        };
+        Self {
+            kind,
+            retryable: true,
+            error: err.into(),
+        }
+    }
+
+    fn from_status(status: reqwest::StatusCode) -> Self {
+        let retryable =
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+        Self {
+            kind: FailureKind::HttpStatus,
+            retryable,
+            error: format_err!("unexpected HTTP status {}", status),
+        }
+    }
+}
+
+/// Bounded retries, with jittered exponential backoff.
+#[derive(Clone, Debug)]
+struct RetryPolicy {
+    max_retries: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Compute the delay before retrying, after `attempt` failed attempts.
+    ///
+    /// The delay doubles at each attempt (up to `max_delay`), and is then
+    /// randomly jittered down to half of its value.
+    fn delay(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+
+        let factor = 2u32.saturating_pow(attempt.min(16));
+        let delay = self
+            .initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let millis = delay.as_millis() as u64;
+        let jittered = rand::thread_rng().gen_range(millis / 2, millis + 1);
+        Duration::from_millis(jittered)
+    }
+}
+
+/// Client for upstream documents.
+#[derive(Clone, Debug)]
+pub(crate) struct UpstreamClient {
+    hclient: reqwest::r#async::Client,
+    retry: RetryPolicy,
+    stream: String,
+}
+
+impl UpstreamClient {
+    pub(crate) fn new(stream: &str, cfg: &config::UpstreamSettings) -> Fallible<Self> {
+        let hclient = reqwest::r#async::ClientBuilder::new()
+            .timeout(cfg.request_timeout)
+            .build()?;
+        let retry = RetryPolicy {
+            max_retries: cfg.max_retries,
+            initial_delay: cfg.retry_initial_delay,
+            max_delay: cfg.retry_max_delay,
+        };
+        let client = Self {
+            hclient,
+            retry,
+            stream: stream.to_string(),
+        };
+        Ok(client)
+    }
+
+    /// Fetch an upstream document, conditionally on it having changed since `cached`.
+    ///
+    /// Transient failures are retried according to the configured policy.
+    pub(crate) fn fetch(
+        &self,
+        upstream: &'static str,
+        url: reqwest::Url,
+        cached: Option<&UpstreamDoc>,
+    ) -> impl Future<Item = Fetched, Error = Error> {
+        if url.scheme() == "file" {
+            let fetched = self.read_file(upstream, &url, cached);
+            return future::Either::A(future::result(fetched));
+        }
+
+        let client = self.clone();
+        let validators = cached.map(|doc| (doc.etag.clone(), doc.last_modified.clone()));
+
+        let fetched = future::loop_fn(0u32, move |attempt| {
+            let client = client.clone();
+            client
+                .try_fetch(url.clone(), validators.clone())
+                .then(move |res| {
+                    let err = match res {
+                        Ok(fetched) => return future::Either::A(future::ok(Loop::Break(fetched))),
+                        Err(err) => err,
+                    };
+
+                    record_failure(&client.stream, upstream, err.kind);
+                    if !err.retryable || attempt >= client.retry.max_retries {
+                        let msg = format_err!(
+                            "failed to fetch {} after {} attempt(s): {}",
+                            upstream,
+                            attempt + 1,
+                            err.error
+                        );
+                        return future::Either::A(future::err(msg));
+                    }
+
+                    let delay = client.retry.delay(attempt);
+                    log::warn!(
+                        "stream '{}': {} request failed ({}), retrying in {}ms: {}",
+                        client.stream,
+                        upstream,
+                        err.kind.as_str(),
+                        delay.as_millis(),
+                        err.error
+                    );
+                    UPSTREAM_RETRIES
+                        .with_label_values(&[&client.stream, upstream])
+                        .inc();
+                    let retry = tokio_timer::Delay::new(Instant::now() + delay)
+                        .from_err()
+                        .map(move |_| Loop::Continue(attempt + 1));
+                    future::Either::B(retry)
+                })
+        });
+        future::Either::B(fetched)
+    }
+
+    /// Read a local upstream document, if modified since `cached`.
+    fn read_file(
+        &self,
+        upstream: &str,
+        url: &reqwest::Url,
+        cached: Option<&UpstreamDoc>,
+    ) -> Fallible<Fetched> {
+        let read = || -> Fallible<Fetched> {
+            let path = url
+                .to_file_path()
+                .map_err(|_| format_err!("invalid file URL '{}'", url))?;
+            let mtime = std::fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .map_err(|e| format_err!("failed to stat '{}': {}", path.display(), e))?;
+            let last_modified = chrono::DateTime::<chrono::Utc>::from(mtime).to_rfc3339();
+            if cached.and_then(|doc| doc.last_modified.as_ref()) == Some(&last_modified) {
+                return Ok(Fetched::Unchanged);
+            }
+
+            let body = std::fs::read(&path)
+                .map_err(|e| format_err!("failed to read '{}': {}", path.display(), e))?;
+            let doc = UpstreamDoc {
+                body,
+                etag: None,
+                last_modified: Some(last_modified),
+            };
+            Ok(Fetched::Changed(doc))
+        };
+
+        read().map_err(|e| {
+            record_failure(&self.stream, upstream, FailureKind::Io);
+            format_err!("failed to fetch {}: {}", upstream, e)
+        })
+    }
+
+    /// Perform a single request attempt.
+    fn try_fetch(
+        &self,
+        url: reqwest::Url,
+        validators: Option<(Option<String>, Option<String>)>,
+    ) -> impl Future<Item = Fetched, Error = AttemptError> {
+        let mut req = self.hclient.get(url);
+        if let Some((etag, last_modified)) = validators {
+            if let Some(etag) = etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        req.send()
+            .map_err(AttemptError::from_reqwest)
+            .and_then(|resp| {
+                let status = resp.status();
+                if status == reqwest::StatusCode::NOT_MODIFIED {
+                    return future::Either::A(future::ok(Fetched::Unchanged));
+                }
+                if !status.is_success() {
+                    return future::Either::A(future::err(AttemptError::from_status(status)));
+                }
+
+                let header_value = |name| {
+                    resp.headers()
+                        .get(name)
+                        .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                        .map(String::from)
+                };
+                let etag = header_value(header::ETAG);
+                let last_modified = header_value(header::LAST_MODIFIED);
+                let doc = resp
+                    .into_body()
+                    .concat2()
+                    .map_err(AttemptError::from_reqwest)
+                    .map(|body| {
+                        Fetched::Changed(UpstreamDoc {
+                            body: body.to_vec(),
+                            etag,
+                            last_modified,
+                        })
+                    });
+                future::Either::B(doc)
+            })
+    }
+}
+
+/// Sources of upstream metadata, for one-shot commands.
+#[derive(Debug, StructOpt)]
+pub(crate) struct SourceOptions {
+    /// Stream, for default metadata sources.
+    #[structopt(long = "stream", default_value = "stable")]
+    pub stream: String,
+    /// Release index, as a path or URL (defaults to the configured upstream).
+    #[structopt(long = "releases")]
+    pub releases: Option<String>,
+    /// Updates metadata, as a path or URL (defaults to the configured upstream).
+    #[structopt(long = "updates")]
+    pub updates: Option<String>,
+}
+
+impl SourceOptions {
+    /// Read and decode release-index and updates metadata.
+    pub(crate) fn load(
+        &self,
+        cfg: &config::UpstreamSettings,
+    ) -> Fallible<(metadata::ReleasesJSON, metadata::UpdatesJSON)> {
+        let source = |input: &Option<String>, template: &str| -> Fallible<String> {
+            match input {
+                Some(src) => Ok(src.clone()),
+                None => {
+                    Ok(config::UpstreamSettings::render_url(template, &self.stream)?.to_string())
+                }
+            }
+        };
+        let releases_src = source(&self.releases, &cfg.releases_url)?;
+        let updates_src = source(&self.updates, &cfg.updates_url)?;
+
+        let releases = serde_json::from_slice(&read_source(&releases_src)?)
+            .map_err(|e| format_err!("failed to decode '{}': {}", releases_src, e))?;
+        let updates = serde_json::from_slice(&read_source(&updates_src)?)
+            .map_err(|e| format_err!("failed to decode '{}': {}", updates_src, e))?;
+        Ok((releases, updates))
+    }
+}
+
+/// Read a metadata document from a local path, or a `file://` or HTTP(S) URL.
+fn read_source(input: &str) -> Fallible<Vec<u8>> {
+    let path = match reqwest::Url::parse(input) {
+        Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {
+            let mut resp = reqwest::get(url.clone())
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| format_err!("failed to fetch '{}': {}", input, e))?;
+            let mut body = vec![];
+            resp.copy_to(&mut body)?;
+            return Ok(body);
+        }
+        Ok(ref url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map_err(|_| format_err!("invalid file URL '{}'", input))?,
+        _ => std::path::PathBuf::from(input),
+    };
+    std::fs::read(&path).map_err(|e| format_err!("failed to read '{}': {}", path.display(), e))
+}
+
+/// Watcher of local files.
+///
+/// Parent directories are watched (instead of the files themselves), so that
+/// atomically replaced files are tracked too. Files are watched for as long as
+/// this is alive.
+pub(crate) struct FileWatcher {
+    paths: Vec<PathBuf>,
+    /// Underlying watcher, which stops watching once dropped.
+    _watcher: notify::RecommendedWatcher,
+    events: Option<std::sync::mpsc::Receiver<notify::DebouncedEvent>>,
+}
+
+impl std::fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FileWatcher")
+            .field("paths", &self.paths)
+            .finish()
+    }
+}
+
+impl FileWatcher {
+    /// Start watching `paths`, failing if any of them cannot be watched.
+    ///
+    /// Changes are buffered until a callback is set via `on_change`.
+    pub(crate) fn new(paths: Vec<PathBuf>) -> Fallible<Self> {
+        use notify::{RecursiveMode, Watcher};
+        use std::collections::BTreeSet;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::watcher(tx, Duration::from_millis(500))?;
+        let dirs: BTreeSet<_> = paths.iter().filter_map(|p| p.parent()).collect();
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| format_err!("failed to watch '{}': {:?}", dir.display(), e))?;
+        }
+
+        let file_watcher = Self {
+            paths,
+            _watcher: watcher,
+            events: Some(rx),
+        };
+        Ok(file_watcher)
+    }
+
+    /// Call `on_change` whenever any of the watched files changes.
+    ///
+    /// Changes are processed by a dedicated thread, which exits once this
+    /// watcher is dropped.
+    pub(crate) fn on_change<F>(&mut self, on_change: F) -> Fallible<()>
+    where
+        F: Fn() + Send + 'static,
+    {
+        use notify::DebouncedEvent;
+
+        let events = match self.events.take() {
+            Some(events) => events,
+            None => bail!("file watcher already has a callback"),
+        };
+        let paths = self.paths.clone();
+        std::thread::Builder::new()
+            .name("dumnati-watcher".to_string())
+            .spawn(move || {
+                for event in events {
+                    let changed = match event {
+                        DebouncedEvent::Create(ref p)
+                        | DebouncedEvent::Write(ref p)
+                        | DebouncedEvent::Chmod(ref p)
+                        | DebouncedEvent::Remove(ref p) => paths.contains(p),
+                        DebouncedEvent::Rename(ref from, ref to) => {
+                            paths.contains(from) || paths.contains(to)
+                        }
+                        DebouncedEvent::Rescan => true,
+                        DebouncedEvent::Error(e, _) => {
+                            log::warn!("file watcher error: {}", e);
+                            false
+                        }
+                        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => false,
+                    };
+                    if changed {
+                        on_change();
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Serve `count` HTTP/1.1 connections on localhost, answering the n-th
+    /// request with the raw response returned by `respond`.
+    fn serve<F>(count: usize, respond: F) -> reqwest::Url
+    where
+        F: Fn(usize, &str) -> String + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (index, conn) in listener.incoming().take(count).enumerate() {
+                let mut conn = conn.unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = conn.read(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                let response = respond(index, &String::from_utf8_lossy(&request).to_lowercase());
+                conn.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        reqwest::Url::parse(&format!("http://{}/updates.json", addr)).unwrap()
+    }
+
+    fn client(stream: &str, max_retries: u32) -> UpstreamClient {
+        UpstreamClient {
+            hclient: reqwest::r#async::ClientBuilder::new().build().unwrap(),
+            retry: RetryPolicy {
+                max_retries,
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            },
+            stream: stream.to_string(),
+        }
+    }
+
+    fn fetch(
+        client: &UpstreamClient,
+        url: reqwest::Url,
+        cached: Option<&UpstreamDoc>,
+    ) -> Fallible<Fetched> {
+        actix::System::new("dumnati-test").block_on(client.fetch("updates", url, cached))
+    }
+
+    fn doc(body: &str) -> UpstreamDoc {
+        UpstreamDoc {
+            body: body.as_bytes().to_vec(),
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn conditional_fetch() {
+        let url = serve(2, |_, request| {
+            if request.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: 2\r\n\r\n{}".to_string()
+            }
+        });
+        let client = client("conditional-fetch", 0);
+
+        let doc = match fetch(&client, url.clone(), None).unwrap() {
+            Fetched::Changed(doc) => doc,
+            Fetched::Unchanged => panic!("unconditional fetch reported unchanged"),
+        };
+        assert_eq!(doc.body, b"{}");
+        assert_eq!(doc.etag.as_ref().map(String::as_str), Some("\"v1\""));
+
+        let fetched = fetch(&client, url, Some(&doc)).unwrap();
+        let cached = Some(doc);
+        match fetched {
+            Fetched::Unchanged => {}
+            Fetched::Changed(doc) => panic!("conditional fetch got {:?}", doc),
+        };
+        assert_eq!(fetched.or_cached(&cached).unwrap().body, b"{}");
+    }
+
+    #[test]
+    fn cached_content() {
+        let cached = Some(doc("{}"));
+        assert_eq!(Fetched::Unchanged.or_cached(&cached).unwrap().body, b"{}");
+        assert!(Fetched::Unchanged.or_cached(&None).is_err());
+        let changed = Fetched::Changed(doc("[]")).or_cached(&cached).unwrap();
+        assert_eq!(changed.body, b"[]");
+
+        assert!(Fetched::same_content(&doc("{}"), &cached));
+        assert!(!Fetched::same_content(&doc("[]"), &cached));
+        assert!(!Fetched::same_content(&doc("{}"), &None));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        for _ in 0..50 {
+            let first = policy.delay(0);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.delay(2);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            for attempt in &[4, 16, 40, u32::max_value()] {
+                let capped = policy.delay(*attempt);
+                assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_secs(1));
+            }
+        }
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        use reqwest::StatusCode;
+
+        for status in &[
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let err = AttemptError::from_status(*status);
+            assert!(err.retryable, "{}", status);
+            assert_eq!(err.kind, FailureKind::HttpStatus);
+        }
+        for status in &[
+            StatusCode::BAD_REQUEST,
+            StatusCode::FORBIDDEN,
+            StatusCode::NOT_FOUND,
+        ] {
+            assert!(!AttemptError::from_status(*status).retryable, "{}", status);
+        }
+    }
+
+    #[test]
+    fn retried_fetch() {
+        let retries = UPSTREAM_RETRIES.with_label_values(&["retried-fetch", "updates"]);
+        let retries_before = retries.get();
+        let url = serve(3, |index, _| match index {
+            0 => "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n".to_string(),
+            1 => "HTTP/1.1 429 Too Many Requests\r\ncontent-length: 0\r\n\r\n".to_string(),
+            _ => "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}".to_string(),
+        });
+        let retried = client("retried-fetch", 2);
+        match fetch(&retried, url, None).unwrap() {
+            Fetched::Changed(doc) => assert_eq!(doc.body, b"{}"),
+            Fetched::Unchanged => panic!("unconditional fetch reported unchanged"),
+        };
+        assert_eq!(retries.get() - retries_before, 2);
+
+        let failures =
+            UPSTREAM_FAILURES.with_label_values(&["missing-fetch", "updates", "http_status"]);
+        let failures_before = failures.get();
+        let url = serve(1, |_, _| {
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_string()
+        });
+        let missing = client("missing-fetch", 2);
+        let err = fetch(&missing, url, None).unwrap_err();
+        assert!(err.to_string().contains("after 1 attempt(s)"), "{}", err);
+        assert_eq!(failures.get() - failures_before, 1);
+    }
+
+    #[test]
+    fn local_files() {
+        let dir = std::env::temp_dir().join(format!("dumnati-local-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("updates.json");
+        std::fs::write(&path, "{}").unwrap();
+        let url = reqwest::Url::from_file_path(&path).unwrap();
+        let client = client("local-files", 0);
+
+        let doc = match fetch(&client, url.clone(), None).unwrap() {
+            Fetched::Changed(doc) => doc,
+            Fetched::Unchanged => panic!("first read reported unchanged"),
+        };
+        assert_eq!(doc.body, b"{}");
+        assert!(doc.last_modified.is_some());
+        match fetch(&client, url.clone(), Some(&doc)).unwrap() {
+            Fetched::Unchanged => {}
+            Fetched::Changed(doc) => panic!("unmodified file read as {:?}", doc),
+        };
+
+        std::fs::write(&path, "[]").unwrap();
+        let stale = UpstreamDoc {
+            last_modified: Some("1970-01-01T00:00:00+00:00".to_string()),
+            ..doc
+        };
+        match fetch(&client, url.clone(), Some(&stale)).unwrap() {
+            Fetched::Changed(doc) => assert_eq!(doc.body, b"[]"),
+            Fetched::Unchanged => panic!("modified file reported unchanged"),
+        };
+
+        let failures = UPSTREAM_FAILURES.with_label_values(&["local-files", "updates", "io"]);
+        let failures_before = failures.get();
+        std::fs::remove_dir_all(&dir).unwrap();
+        fetch(&client, url, None).unwrap_err();
+        assert_eq!(failures.get() - failures_before, 1);
+    }
+
+    #[test]
+    fn watched_files() {
+        let dir = std::env::temp_dir().join(format!("dumnati-watched-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("updates.json");
+        std::fs::write(&path, "{}").unwrap();
+
+        assert!(FileWatcher::new(vec![dir.join("missing").join("updates.json")]).is_err());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = FileWatcher::new(vec![path.clone()]).unwrap();
+        // Changes before the callback is set are not lost.
+        std::fs::write(&path, "[]").unwrap();
+        watcher
+            .on_change(move || {
+                let _ = tx.send(());
+            })
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(watcher.on_change(|| ()).is_err());
+
+        std::fs::write(dir.join("unrelated.json"), "{}").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_err());
+        std::fs::write(&path, "{}").unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // The watcher thread, and thus the callback, go away with the watcher.
+        drop(watcher);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(10)),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
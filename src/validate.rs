@@ -0,0 +1,574 @@
+//! Sanity checks for upstream metadata and the resulting graph.
+
+use crate::graph::Graph;
+use crate::{config, metadata, policy, upstream};
+use failure::{bail, Fallible};
+use std::collections::HashSet;
+
+/// Validate upstream metadata, printing findings to stdout.
+///
+/// This fails if any hard check fails.
+pub(crate) fn run(settings: config::Settings, opts: upstream::SourceOptions) -> Fallible<()> {
+    let (releases, updates) = opts.load(&settings.upstream)?;
+    let mut report = check_metadata(&releases.releases, &updates, &settings.upstream.basearches);
+    let graph = Graph::from_metadata(releases.releases, updates)?;
+    report.extend(check_graph(&graph));
+
+    for finding in &report.findings {
+        println!("{}", finding);
+    }
+    let errors = report.errors().count();
+    if errors > 0 {
+        bail!("stream '{}': {} hard check(s) failed", opts.stream, errors);
+    }
+    println!(
+        "stream '{}': {} releases, {} warning(s)",
+        opts.stream,
+        graph.nodes.len(),
+        report.findings.len()
+    );
+    Ok(())
+}
+
+/// Severity of a validation finding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Severity {
+    /// Metadata is broken, the graph must not be served.
+    Error,
+    /// Metadata is suspicious, but the graph can be served.
+    Warning,
+}
+
+impl Severity {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Single validation finding.
+#[derive(Clone, Debug)]
+pub(crate) struct Finding {
+    pub(crate) severity: Severity,
+    pub(crate) kind: &'static str,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{}]: {}",
+            self.severity.as_str(),
+            self.kind,
+            self.message
+        )
+    }
+}
+
+/// Validation findings.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Report {
+    pub(crate) findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Whether any hard check failed.
+    pub(crate) fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Iterate over findings which fail hard checks.
+    pub(crate) fn errors(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+    }
+
+    /// Append all findings of `other`.
+    pub(crate) fn extend(&mut self, other: Report) {
+        self.findings.extend(other.findings);
+    }
+
+    fn push(&mut self, severity: Severity, kind: &'static str, message: String) {
+        self.findings.push(Finding {
+            severity,
+            kind,
+            message,
+        });
+    }
+}
+
+/// Check release-index and updates metadata, with every release expected to
+/// have a payload for each of `basearches`.
+///
+/// Releases missing some of them are only reported, and are dropped for those
+/// basearches by the `pick_basearch` policy; releases missing all of them, and
+/// barriers missing any of them, are errors.
+pub(crate) fn check_metadata(
+    releases: &[metadata::Release],
+    updates: &metadata::UpdatesJSON,
+    basearches: &[String],
+) -> Report {
+    let mut report = Report::default();
+
+    let barriers: HashSet<_> = updates
+        .releases
+        .iter()
+        .filter(|entry| entry.metadata.barrier.is_some())
+        .map(|entry| entry.version.as_str())
+        .collect();
+    let mut versions = HashSet::with_capacity(releases.len());
+    for release in releases {
+        if !versions.insert(release.version.as_str()) {
+            report.push(
+                Severity::Error,
+                "duplicate_version",
+                format!("release '{}' listed multiple times", release.version),
+            );
+        }
+
+        let missing: Vec<_> = basearches
+            .iter()
+            .filter(|basearch| {
+                !release
+                    .commits
+                    .iter()
+                    .any(|c| &&c.architecture == basearch && !c.checksum.is_empty())
+            })
+            .collect();
+        if !basearches.is_empty() && missing.len() == basearches.len() {
+            report.push(
+                Severity::Error,
+                "missing_payload",
+                format!(
+                    "release '{}' has no payload for any basearch",
+                    release.version
+                ),
+            );
+        } else if barriers.contains(release.version.as_str()) {
+            for basearch in missing {
+                report.push(
+                    Severity::Error,
+                    "missing_payload",
+                    format!(
+                        "barrier release '{}' has no payload for basearch '{}'",
+                        release.version, basearch
+                    ),
+                );
+            }
+        } else {
+            for basearch in missing {
+                report.push(
+                    Severity::Warning,
+                    "missing_basearch",
+                    format!(
+                        "release '{}' has no payload for basearch '{}'",
+                        release.version, basearch
+                    ),
+                );
+            }
+        }
+
+        let checksums = release
+            .commits
+            .iter()
+            .filter(|c| !c.architecture.is_empty() && !c.checksum.is_empty())
+            .count();
+        if checksums == 0 {
+            report.push(
+                Severity::Warning,
+                "missing_checksums",
+                format!(
+                    "release '{}' has no architecture checksums",
+                    release.version
+                ),
+            );
+        } else if checksums < release.commits.len() {
+            report.push(
+                Severity::Warning,
+                "missing_checksums",
+                format!(
+                    "release '{}' has commits without architecture or checksum",
+                    release.version
+                ),
+            );
+        }
+    }
+
+    let mut updated = HashSet::with_capacity(updates.releases.len());
+    for entry in &updates.releases {
+        if !updated.insert(entry.version.as_str()) {
+            report.push(
+                Severity::Error,
+                "duplicate_version",
+                format!("update entry for '{}' listed multiple times", entry.version),
+            );
+        }
+        if !versions.contains(entry.version.as_str()) {
+            report.push(
+                Severity::Warning,
+                "orphaned_update",
+                format!(
+                    "update entry for '{}' has no matching release",
+                    entry.version
+                ),
+            );
+        }
+        if entry.metadata.barrier.is_some() && entry.metadata.deadend.is_some() {
+            report.push(
+                Severity::Error,
+                "conflicting_markers",
+                format!(
+                    "release '{}' is both a barrier and a dead-end",
+                    entry.version
+                ),
+            );
+        }
+        if let Some(rollout) = &entry.metadata.rollout {
+            check_rollout(&mut report, &entry.version, rollout);
+        }
+    }
+
+    report
+}
+
+/// Check the parameters of a rollout, which would otherwise silently stall.
+fn check_rollout(report: &mut Report, version: &str, rollout: &metadata::UpdateRollout) {
+    let curve = rollout
+        .curve
+        .as_ref()
+        .map(String::as_str)
+        .unwrap_or(metadata::CURVE_LINEAR);
+    if !metadata::ROLLOUT_CURVES.contains(&curve) {
+        report.push(
+            Severity::Error,
+            "unknown_rollout_curve",
+            format!(
+                "release '{}' has unknown rollout curve '{}'",
+                version, curve
+            ),
+        );
+    }
+    if let Some(state) = &rollout.state {
+        if !metadata::ROLLOUT_STATES.contains(&state.as_str()) {
+            report.push(
+                Severity::Error,
+                "unknown_rollout_state",
+                format!(
+                    "release '{}' has unknown rollout state '{}'",
+                    version, state
+                ),
+            );
+        }
+    }
+    let has_steps = rollout
+        .steps
+        .as_ref()
+        .map_or(false, |steps| !steps.is_empty());
+    if curve == metadata::CURVE_STEPPED && !has_steps {
+        report.push(
+            Severity::Error,
+            "missing_rollout_steps",
+            format!("release '{}' has a stepped rollout without steps", version),
+        );
+    }
+}
+
+/// Check the assembled graph.
+pub(crate) fn check_graph(graph: &Graph) -> Report {
+    let mut report = Report::default();
+    if graph.nodes.is_empty() {
+        return report;
+    }
+
+    // Every release should be reachable from the oldest one which can be updated
+    // from, once dead-ends are pruned. Older dead-ends are not expected to be.
+    let anchor = match graph
+        .nodes
+        .iter()
+        .position(|n| !n.metadata.contains_key(metadata::DEADEND))
+    {
+        Some(anchor) => anchor,
+        None => return report,
+    };
+    let mut explain = policy::Explanation::disabled();
+    let pruned = policy::filter_deadends(graph.clone(), &mut explain);
+    let reachable = policy::trim_to_reachable(pruned, anchor, &mut explain);
+    let reachable: HashSet<_> = reachable.nodes.iter().map(|n| &n.version).collect();
+    let origin = &graph.nodes[anchor].version;
+    for node in &graph.nodes[anchor..] {
+        if !reachable.contains(&node.version) {
+            report.push(
+                Severity::Warning,
+                "unreachable_release",
+                format!(
+                    "release '{}' is not reachable from release '{}'",
+                    node.version, origin
+                ),
+            );
+        }
+    }
+
+    report
+}
+
+/// Check a refreshed graph against the one being served, for suspicious regressions.
+pub(crate) fn check_regression(
+    previous: &Graph,
+    next: &Graph,
+    checks: &config::RegressionChecks,
+) -> Report {
+    let mut report = Report::default();
+    if previous.nodes.is_empty() {
+        return report;
+    }
+
+    let (before, after) = (previous.nodes.len(), next.nodes.len());
+    let dropped = before.saturating_sub(after);
+    if dropped * 100 > before * usize::from(checks.max_release_drop_percent) {
+        report.push(
+            Severity::Error,
+            "release_count_drop",
+            format!(
+                "number of releases dropped from {} to {} (more than {}%)",
+                before, after, checks.max_release_drop_percent
+            ),
+        );
+    }
+
+    if !checks.allow_latest_release_removal {
+        let latest = &previous.nodes[previous.nodes.len() - 1].version;
+        if !next.nodes.iter().any(|n| &n.version == latest) {
+            report.push(
+                Severity::Error,
+                "latest_release_removed",
+                format!("latest release '{}' disappeared", latest),
+            );
+        }
+    }
+
+    if !checks.allow_barrier_changes {
+        let barriers = |graph: &Graph| -> HashSet<String> {
+            graph
+                .nodes
+                .iter()
+                .filter(|n| n.metadata.contains_key(metadata::BARRIER))
+                .map(|n| n.version.clone())
+                .collect()
+        };
+        let (old, new) = (barriers(previous), barriers(next));
+        if old != new {
+            let mut added: Vec<_> = new.difference(&old).map(String::as_str).collect();
+            let mut removed: Vec<_> = old.difference(&new).map(String::as_str).collect();
+            added.sort();
+            removed.sort();
+            report.push(
+                Severity::Error,
+                "barriers_changed",
+                format!(
+                    "barriers changed (added: [{}], removed: [{}])",
+                    added.join(", "),
+                    removed.join(", ")
+                ),
+            );
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::graph_with_markers;
+
+    #[test]
+    fn metadata_findings() {
+        let releases: Vec<metadata::Release> = serde_json::from_value(serde_json::json!([
+            { "version": "30.0", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "sha0" },
+            ] },
+            { "version": "30.1", "metadata": "", "commits": [] },
+            { "version": "30.2", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "sha2" },
+            ] },
+            { "version": "30.2", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "sha2" },
+            ] },
+            { "version": "30.3", "metadata": "", "commits": [
+                { "architecture": "x86_64", "checksum": "sha3" },
+            ] },
+        ]))
+        .unwrap();
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [
+                { "version": "29.9", "metadata": { "deadend": { "reason": "" } } },
+                { "version": "30.1", "metadata": {
+                    "barrier": { "reason": "" }, "deadend": { "reason": "" },
+                } },
+            ],
+        }))
+        .unwrap();
+
+        let basearches = vec!["x86_64".to_string()];
+        let report = check_metadata(&releases, &updates, &basearches);
+        let kinds: Vec<_> = report
+            .findings
+            .iter()
+            .map(|f| (f.severity, f.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (Severity::Error, "missing_payload"),
+                (Severity::Warning, "missing_checksums"),
+                (Severity::Error, "duplicate_version"),
+                (Severity::Warning, "orphaned_update"),
+                (Severity::Error, "conflicting_markers"),
+            ]
+        );
+        assert!(report.has_errors());
+        let basearches = vec!["x86_64".to_string(), "aarch64".to_string()];
+        let barrier: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "stream": "testing",
+            "releases": [{ "version": "30.2", "metadata": { "barrier": { "reason": "" } } }],
+        }))
+        .unwrap();
+        let report = check_metadata(&releases[..3], &barrier, &basearches);
+        let payloads: Vec<_> = report
+            .findings
+            .iter()
+            .filter(|f| f.kind.starts_with("missing_") && f.kind != "missing_checksums")
+            .map(|f| (f.severity, f.message.as_str()))
+            .collect();
+        assert_eq!(
+            payloads,
+            vec![
+                (
+                    Severity::Warning,
+                    "release '30.0' has no payload for basearch 'aarch64'"
+                ),
+                (
+                    Severity::Error,
+                    "release '30.1' has no payload for any basearch"
+                ),
+                (
+                    Severity::Error,
+                    "barrier release '30.2' has no payload for basearch 'aarch64'"
+                ),
+            ]
+        );
+
+        let releases: Vec<_> = releases.into_iter().take(3).collect();
+        let graph = Graph::from_metadata(releases, updates).unwrap();
+        let report = check_graph(&graph);
+        let unreachable: Vec<_> = report.findings.iter().map(|f| &f.message).collect();
+        assert_eq!(
+            unreachable,
+            vec!["release '30.2' is not reachable from release '30.0'"]
+        );
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn reachability_findings() {
+        let messages = |markers: &[&str]| -> Vec<_> {
+            let report = check_graph(&graph_with_markers(markers));
+            report.findings.into_iter().map(|f| f.message).collect()
+        };
+        assert!(messages(&[]).is_empty());
+        assert!(messages(&["deadend", "deadend"]).is_empty());
+        assert!(messages(&["deadend", "", "barrier", ""]).is_empty());
+        assert!(messages(&["deadend", "deadend", "", ""]).is_empty());
+        assert_eq!(
+            messages(&["deadend", "", "barrier,deadend", ""]),
+            vec!["release '30.3' is not reachable from release '30.1'"]
+        );
+    }
+
+    #[test]
+    fn rollout_findings() {
+        let updates: metadata::UpdatesJSON = serde_json::from_value(serde_json::json!({
+            "releases": [
+                { "version": "30.0", "metadata": { "rollout": { "curve": "linear" } } },
+                { "version": "30.1", "metadata": { "rollout": { "curve": "sigmoid" } } },
+                { "version": "30.2", "metadata": { "rollout": { "curve": "stepped" } } },
+                { "version": "30.3", "metadata": { "rollout": { "curve": "stepped", "steps": [] } } },
+                { "version": "30.4", "metadata": { "rollout": { "curve": "stepped", "steps": [
+                    { "offset_minutes": 0, "percentage": 0.5 },
+                ] } } },
+                { "version": "30.5", "metadata": { "rollout": {} } },
+                { "version": "30.6", "metadata": { "rollout": { "state": "paused" } } },
+                { "version": "30.7", "metadata": { "rollout": { "state": "halted" } } },
+            ],
+        }))
+        .unwrap();
+        let releases = metadata::test_releases(&[
+            "30.0", "30.1", "30.2", "30.3", "30.4", "30.5", "30.6", "30.7",
+        ]);
+
+        let report = check_metadata(&releases, &updates, &[]);
+        let errors: Vec<_> = report.errors().map(|f| f.message.as_str()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "release '30.1' has unknown rollout curve 'sigmoid'",
+                "release '30.2' has a stepped rollout without steps",
+                "release '30.3' has a stepped rollout without steps",
+                "release '30.7' has unknown rollout state 'halted'",
+            ]
+        );
+    }
+
+    #[test]
+    fn regression_findings() {
+        let graph = |versions: &[&str], barriers: &[&str]| {
+            let releases = metadata::test_releases(versions);
+            let entries: Vec<_> = barriers
+                .iter()
+                .map(|v| serde_json::json!({ "version": v, "metadata": { "barrier": { "reason": "" } } }))
+                .collect();
+            let updates = serde_json::from_value(serde_json::json!({
+                "stream": "testing",
+                "releases": entries,
+            }))
+            .unwrap();
+            Graph::from_metadata(releases, updates).unwrap()
+        };
+        let kinds = |report: Report| -> Vec<_> { report.findings.iter().map(|f| f.kind).collect() };
+        let mut checks = config::RegressionChecks {
+            max_release_drop_percent: 20,
+            allow_latest_release_removal: false,
+            allow_barrier_changes: false,
+        };
+
+        let previous = graph(&["30.0", "30.1", "30.2", "30.3", "30.4"], &["30.1"]);
+        let empty = Graph::default();
+        assert!(kinds(check_regression(&empty, &previous, &checks)).is_empty());
+
+        let next = graph(&["30.1", "30.2", "30.3", "30.4", "30.5"], &["30.1"]);
+        assert!(kinds(check_regression(&previous, &next, &checks)).is_empty());
+
+        let next = graph(&["30.1", "30.2", "30.3", "30.4"], &["30.1"]);
+        assert!(kinds(check_regression(&previous, &next, &checks)).is_empty());
+
+        let next = graph(&["30.0", "30.1", "30.2"], &["30.1", "30.2"]);
+        assert_eq!(
+            kinds(check_regression(&previous, &next, &checks)),
+            vec![
+                "release_count_drop",
+                "latest_release_removed",
+                "barriers_changed"
+            ]
+        );
+
+        checks.max_release_drop_percent = 100;
+        checks.allow_latest_release_removal = true;
+        checks.allow_barrier_changes = true;
+        assert!(kinds(check_regression(&previous, &next, &checks)).is_empty());
+    }
+}